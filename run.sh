@@ -2,4 +2,4 @@ cargo clean
 # docker build -t marshal .
 docker build -t marshal .
 # docker run --rm -it --entrypoint bash marshal
-docker run --rm marshal cargo run --release > result.txt
\ No newline at end of file
+docker run --rm marshal cargo run --release -- run > result.txt
\ No newline at end of file
@@ -0,0 +1,72 @@
+use crate::dataflow::Dataflow;
+use crate::error::MarshalError;
+use crate::pipeline::PipelineState;
+use crate::reorder::ReorderBuffer;
+use crate::sources::RecordSource;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Write};
+
+// Everything needed to continue a run where it stopped: how far the input was read, the
+// records read but not released by the reorder buffer yet, the state of every stage and
+// how much of every sink was written.
+// Saved with borrowed state, so writing one does not copy the collections
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Checkpoint<'a> {
+    pub input: String,
+    pub dataflow: String,
+    pub offset: usize, // items read from the input, bad records included
+    pub reorder: Cow<'a, ReorderBuffer>,
+    pub pipeline: PipelineState<'a>,
+    pub sinks: Vec<(String, u64)>, // path and length, see Sink::checkpoint
+}
+
+impl Checkpoint<'_> {
+    // Written next to `path` first and then renamed over it, so a crash while saving
+    // leaves the previous checkpoint intact
+    pub fn save(&self, path: &str) -> Result<(), MarshalError> {
+        let tmp = format!("{}.tmp", path);
+        let mut writer = BufWriter::new(File::create(&tmp)?);
+        bincode::serialize_into(&mut writer, self)?;
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    pub fn load(path: &str) -> Result<Checkpoint<'static>, MarshalError> {
+        let reader = BufReader::new(File::open(path)?);
+        Ok(bincode::deserialize_from(reader)?)
+    }
+
+    // A checkpoint only fits the input and dataflow it was taken from
+    pub fn check(&self, input: &str, dataflow: &Dataflow) -> Result<(), MarshalError> {
+        if self.input != input {
+            return Err(MarshalError::Config(format!(
+                "the checkpoint was taken from {}, not {}",
+                self.input, input
+            )));
+        }
+        if self.dataflow != dataflow.to_string() {
+            return Err(MarshalError::Config(format!(
+                "the checkpoint was taken with the stages {}, not {}",
+                self.dataflow, dataflow
+            )));
+        }
+        Ok(())
+    }
+}
+
+// Reads past the first `offset` items of a source, which a checkpoint already covers
+pub fn skip(source: &mut RecordSource, offset: usize) -> Result<(), MarshalError> {
+    for skipped in 0..offset {
+        if source.next().is_none() {
+            return Err(MarshalError::Config(format!(
+                "the input ends after {} records, the checkpoint is at {}",
+                skipped, offset
+            )));
+        }
+    }
+    Ok(())
+}
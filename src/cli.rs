@@ -0,0 +1,165 @@
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use marshal::predictor::PredictorKind;
+use marshal::sinks::{Geometry, SinkSpec};
+use marshal::sources::ColumnMapping;
+use marshal::StageSpec;
+
+#[derive(Parser, Debug)]
+#[command(
+    name = "marshal",
+    version,
+    about = "Mobility analytics pipeline for benchmarking IoT and Cloud nodes"
+)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Process a trajectory file once and report the per-stage latencies and throughput
+    Run(RunArgs),
+    /// Process a trajectory file several times, one report line per iteration
+    Bench {
+        #[command(flatten)]
+        run: RunArgs,
+
+        /// How many times the dataset is replayed
+        #[arg(long, default_value_t = 5)]
+        repeat: usize,
+    },
+    /// Check that the input, POI, config and model files can be loaded
+    Validate(RunArgs),
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct RunArgs {
+    /// Trajectory file with oid,t,lon,lat (and optionally sog,cog,heading,nav_status,ship_type)
+    /// columns (.csv, .parquet or .arrow), raw AIVDM sentences (.nmea) or a live
+    /// `tcp://host:port` / `udp://host:port` listener (add `?idle=<seconds>` to stop when idle)
+    #[arg(short, long, default_value = "brest.csv")]
+    pub input: String,
+
+    /// Input columns holding the record fields, e.g. `oid=mmsi,t=ts,lon=longitude,lat=latitude`
+    #[arg(long, default_value = "")]
+    pub columns: ColumnMapping,
+
+    /// POI file with x,y columns
+    #[arg(short, long, default_value = "ports_brest.csv")]
+    pub pois: String,
+
+    /// What the predict stage runs: `torch` (a TorchScript model, needs libtorch), `onnx`
+    /// (an ONNX model run by tract) or one of the kinematic baselines `dead_reckoning`
+    /// (last speed and bearing), `ctrv` (constant turn rate and velocity) and `regression`
+    /// (straight line fitted to the last points) (overrides the config)
+    #[arg(long)]
+    pub predictor: Option<PredictorKind>,
+
+    /// Model used by the predict stage, TorchScript or ONNX depending on the predictor
+    /// (overrides the config)
+    #[arg(short, long)]
+    pub model: Option<String>,
+
+    /// Pipeline config (.toml or .json), falls back to MARSHAL_CONFIG
+    #[arg(short, long, env = "MARSHAL_CONFIG")]
+    pub config: Option<String>,
+
+    /// Where the report is written, stdout if omitted
+    #[arg(short, long)]
+    pub output: Option<String>,
+
+    /// Stages to execute, in order. `stage:input` chains a stage to the output
+    /// of an earlier one (e.g. `clean,compress:clean,resample,predict:resample`),
+    /// predict defaults to the clean collection
+    #[arg(
+        short,
+        long,
+        value_delimiter = ',',
+        default_value = "clean,resample,compress,predict"
+    )]
+    pub stages: Vec<StageSpec>,
+
+    /// Write the points a stage emits to a file, `stage=path` (repeatable).
+    /// The format follows the extension: .csv, .jsonl, .geojson, .parquet or .arrow
+    #[arg(long = "sink")]
+    pub sinks: Vec<SinkSpec>,
+
+    /// Write the trajectories of objects evicted for being idle (see `idle_timeout` in the
+    /// config) to this file, in any of the sink formats
+    #[arg(long)]
+    pub evicted_sink: Option<String>,
+
+    /// GeoJSON sinks write a LineString per trip or a Point per record
+    #[arg(long, default_value = "trips")]
+    pub geometry: Geometry,
+
+    /// Replay the input in real time, paced by the record timestamps and sped up by this
+    /// factor (e.g. `1`, `10x`, `100x`). Reports how far behind every stage falls
+    #[arg(long, value_parser = parse_speedup)]
+    pub replay: Option<f64>,
+
+    /// CSV file that receives the records arriving too late to be reordered
+    /// (with `on_late = "side"` in the config)
+    #[arg(long)]
+    pub late_output: Option<String>,
+
+    /// Worker threads, each running the stages for its share of the objects (records are
+    /// split by oid, so every object keeps its order). 1 runs everything on the main thread
+    #[arg(long, default_value_t = 1)]
+    pub threads: usize,
+
+    /// Run every stage on its own thread, fed by the stage it reads from
+    #[arg(long, conflicts_with = "threads")]
+    pub pipelined: bool,
+
+    /// Records the queue in front of every shard (--threads) or stage (--pipelined) holds
+    /// before the thread feeding it has to wait
+    #[arg(long, default_value_t = 1024)]
+    pub queue_size: usize,
+
+    /// Save the state of every stage, the records waiting to be reordered, the position
+    /// in the input and how much of every sink was written to this file every
+    /// `--checkpoint-every` records and at the end. The sinks must be .csv or .jsonl
+    #[arg(long)]
+    pub checkpoint: Option<String>,
+
+    /// Records between two checkpoints (0 only saves one at the end of the run)
+    #[arg(long, default_value_t = 10000)]
+    pub checkpoint_every: usize,
+
+    /// Continue from the `--checkpoint` file if it exists. The sinks are cut back to where
+    /// the checkpoint left them and appended to, the report only covers the records after it
+    #[arg(long, requires = "checkpoint")]
+    pub resume: bool,
+
+    /// Records processed before the latencies and throughput are measured
+    #[arg(long, default_value_t = 0)]
+    pub warmup: usize,
+
+    /// Sample the number of objects, points and heap bytes every stage keeps every
+    /// this many records (0 disables the samples, the final state is always reported)
+    #[arg(long, default_value_t = 1000)]
+    pub state_every: usize,
+
+    /// Report layout: a summary line per run or a JSON object per run with the config,
+    /// dataset, per-stage latencies and volumes, peak memory and host details
+    #[arg(long, value_enum, default_value_t = ReportFormat::Text)]
+    pub format: ReportFormat,
+
+    /// Hide the progress bar
+    #[arg(short, long)]
+    pub quiet: bool,
+}
+
+fn parse_speedup(s: &str) -> Result<f64, String> {
+    match s.trim_end_matches('x').parse::<f64>() {
+        Ok(speedup) if speedup > 0.0 => Ok(speedup),
+        _ => Err(format!("expected a positive speed-up factor, got '{}'", s)),
+    }
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportFormat {
+    Text,
+    Json,
+}
@@ -0,0 +1,235 @@
+use crate::dataflow::Stage;
+use crate::error::MarshalError;
+use crate::sinks::{points, Sink};
+use crate::structs::Trajectory;
+use arrow::array::{
+    ArrayRef, Float32Builder, Int32Builder, Int8Builder, ListBuilder, StringBuilder, UInt8Builder,
+};
+use arrow::datatypes::{DataType, Field, Schema, SchemaRef};
+use arrow::ipc::writer::FileWriter;
+use arrow::record_batch::RecordBatch;
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::file::properties::WriterProperties;
+use std::fs::File;
+use std::sync::Arc;
+
+// Rows buffered by the columnar sinks before a batch is written
+pub static BATCH_SIZE: usize = 8192;
+
+// Same columns as the csv sink, gps is a list of the flocked oids and the reported
+// fields are null when the input does not carry them
+pub fn schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![
+        Field::new("stage", DataType::Utf8, false),
+        Field::new("oid", DataType::Int32, false),
+        Field::new("lon", DataType::Float32, false),
+        Field::new("lat", DataType::Float32, false),
+        Field::new("speed", DataType::Float32, false),
+        Field::new("bearing", DataType::Float32, false),
+        Field::new("stoped", DataType::Int8, false),
+        Field::new("trip", DataType::Int32, false),
+        Field::new("timestamp", DataType::Int32, false),
+        Field::new("poi_id", DataType::Int32, false),
+        Field::new_list("gps", Field::new("item", DataType::Int32, true), false),
+        Field::new("sog", DataType::Float32, true),
+        Field::new("cog", DataType::Float32, true),
+        Field::new("heading", DataType::Float32, true),
+        Field::new("nav_status", DataType::UInt8, true),
+        Field::new("ship_type", DataType::UInt8, true),
+    ]))
+}
+
+// Collects the points of many trajectories into a single RecordBatch
+pub struct PointBatchBuilder {
+    stage: StringBuilder,
+    oid: Int32Builder,
+    lon: Float32Builder,
+    lat: Float32Builder,
+    speed: Float32Builder,
+    bearing: Float32Builder,
+    stoped: Int8Builder,
+    trip: Int32Builder,
+    timestamp: Int32Builder,
+    poi_id: Int32Builder,
+    gps: ListBuilder<Int32Builder>,
+    sog: Float32Builder,
+    cog: Float32Builder,
+    heading: Float32Builder,
+    nav_status: UInt8Builder,
+    ship_type: UInt8Builder,
+    rows: usize,
+}
+
+impl Default for PointBatchBuilder {
+    fn default() -> PointBatchBuilder {
+        PointBatchBuilder::new()
+    }
+}
+
+impl PointBatchBuilder {
+    pub fn new() -> PointBatchBuilder {
+        PointBatchBuilder {
+            stage: StringBuilder::new(),
+            oid: Int32Builder::new(),
+            lon: Float32Builder::new(),
+            lat: Float32Builder::new(),
+            speed: Float32Builder::new(),
+            bearing: Float32Builder::new(),
+            stoped: Int8Builder::new(),
+            trip: Int32Builder::new(),
+            timestamp: Int32Builder::new(),
+            poi_id: Int32Builder::new(),
+            gps: ListBuilder::new(Int32Builder::new()),
+            sog: Float32Builder::new(),
+            cog: Float32Builder::new(),
+            heading: Float32Builder::new(),
+            nav_status: UInt8Builder::new(),
+            ship_type: UInt8Builder::new(),
+            rows: 0,
+        }
+    }
+
+    pub fn append(&mut self, stage: Stage, traj: &Trajectory) {
+        for point in points(stage, traj) {
+            self.stage.append_value(point.stage);
+            self.oid.append_value(point.oid);
+            self.lon.append_value(point.lon);
+            self.lat.append_value(point.lat);
+            self.speed.append_value(point.speed);
+            self.bearing.append_value(point.bearing);
+            self.stoped.append_value(point.stoped);
+            self.trip.append_value(point.trip);
+            self.timestamp.append_value(point.timestamp);
+            self.poi_id.append_value(point.poi_id);
+            self.gps.values().append_slice(point.gps);
+            self.gps.append(true);
+            self.sog.append_option(point.sog);
+            self.cog.append_option(point.cog);
+            self.heading.append_option(point.heading);
+            self.nav_status.append_option(point.nav_status);
+            self.ship_type.append_option(point.ship_type);
+            self.rows += 1;
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.rows
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rows == 0
+    }
+
+    // Builds the batch and leaves the builder empty for the next one
+    pub fn finish(&mut self) -> Result<RecordBatch, MarshalError> {
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(self.stage.finish()),
+            Arc::new(self.oid.finish()),
+            Arc::new(self.lon.finish()),
+            Arc::new(self.lat.finish()),
+            Arc::new(self.speed.finish()),
+            Arc::new(self.bearing.finish()),
+            Arc::new(self.stoped.finish()),
+            Arc::new(self.trip.finish()),
+            Arc::new(self.timestamp.finish()),
+            Arc::new(self.poi_id.finish()),
+            Arc::new(self.gps.finish()),
+            Arc::new(self.sog.finish()),
+            Arc::new(self.cog.finish()),
+            Arc::new(self.heading.finish()),
+            Arc::new(self.nav_status.finish()),
+            Arc::new(self.ship_type.finish()),
+        ];
+        self.rows = 0;
+        Ok(RecordBatch::try_new(schema(), columns)?)
+    }
+}
+
+pub fn to_record_batch(stage: Stage, traj: &Trajectory) -> Result<RecordBatch, MarshalError> {
+    let mut builder = PointBatchBuilder::new();
+    builder.append(stage, traj);
+    builder.finish()
+}
+
+pub struct ParquetSink {
+    writer: Option<ArrowWriter<File>>,
+    builder: PointBatchBuilder,
+    batch_size: usize,
+}
+
+impl ParquetSink {
+    pub fn create(path: &str, batch_size: usize) -> Result<ParquetSink, MarshalError> {
+        let props = WriterProperties::builder()
+            .set_compression(Compression::SNAPPY)
+            .build();
+        let writer = ArrowWriter::try_new(File::create(path)?, schema(), Some(props))?;
+        Ok(ParquetSink {
+            writer: Some(writer),
+            builder: PointBatchBuilder::new(),
+            batch_size,
+        })
+    }
+
+    fn flush_batch(&mut self) -> Result<(), MarshalError> {
+        if let Some(writer) = self.writer.as_mut() {
+            if !self.builder.is_empty() {
+                writer.write(&self.builder.finish()?)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Sink for ParquetSink {
+    fn write(&mut self, stage: Stage, traj: &Trajectory) -> Result<(), MarshalError> {
+        self.builder.append(stage, traj);
+        if self.builder.len() >= self.batch_size {
+            self.flush_batch()?;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), MarshalError> {
+        self.flush_batch()?;
+        if let Some(writer) = self.writer.take() {
+            writer.close()?;
+        }
+        Ok(())
+    }
+}
+
+// Arrow IPC file, for tools that read Arrow directly
+pub struct ArrowIpcSink {
+    writer: FileWriter<File>,
+    builder: PointBatchBuilder,
+    batch_size: usize,
+}
+
+impl ArrowIpcSink {
+    pub fn create(path: &str, batch_size: usize) -> Result<ArrowIpcSink, MarshalError> {
+        Ok(ArrowIpcSink {
+            writer: FileWriter::try_new(File::create(path)?, &schema())?,
+            builder: PointBatchBuilder::new(),
+            batch_size,
+        })
+    }
+}
+
+impl Sink for ArrowIpcSink {
+    fn write(&mut self, stage: Stage, traj: &Trajectory) -> Result<(), MarshalError> {
+        self.builder.append(stage, traj);
+        if self.builder.len() >= self.batch_size {
+            self.writer.write(&self.builder.finish()?)?;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), MarshalError> {
+        if !self.builder.is_empty() {
+            self.writer.write(&self.builder.finish()?)?;
+        }
+        self.writer.finish()?;
+        Ok(())
+    }
+}
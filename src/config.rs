@@ -0,0 +1,256 @@
+use crate::error::{ErrorPolicy, MarshalError};
+use crate::predictor::PredictorKind;
+use crate::reorder::LatePolicy;
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+// Prefix of the environment variables that override values read from the config file,
+// e.g. MARSHAL_MAX_SPEED=40 or MARSHAL_MODEL_PATH=other.pth
+static ENV_PREFIX: &str = "MARSHAL_";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PipelineConfig {
+    pub max_speed: f32,                 // knots
+    pub rate: i32,                      // seconds
+    pub stop_speed_thr: f32,            // knots
+    pub distance_to_poi_thr: f32,       // nmiles
+    pub history_size: usize,            // how many records should I keep in mem
+    pub history_window: i32,            // seconds of history kept per object, 0 keeps everything
+    pub idle_timeout: i32, // seconds without a record before an object is evicted, 0 never
+    pub flocks_distance_threshold: f32, // nmiles
+    pub flocks_max_dt_threshold: i32, // seconds
+    pub flocks_max_bearing_threshold: f32,
+    pub comp_thr: f32,
+    pub opw_epsilon: f32,
+    pub predictor: PredictorKind, // torch, onnx, dead_reckoning, ctrv or regression
+    pub model_path: String,       // TorchScript
+    pub onnx_model_path: String,
+    pub predict_batch_size: usize, // trajectories predicted together, 1 predicts every record on its own
+    // milliseconds a batch waits to fill, 0 until it is full. Only the --threads/--pipelined
+    // workers keep to it on a timer, a single pipeline runs an overdue batch with its next record
+    pub predict_batch_wait: f64,
+    pub predict_horizons: Vec<i32>, // seconds ahead predictions are rolled out to, 0 for the next point
+    pub predict_step: i32,          // seconds between predicted points, 0 for `rate`
+    pub on_error: ErrorPolicy,      // skip or abort on records that cannot be processed
+    pub prefer_reported: bool,      // use the SOG/COG a record reports instead of deriving them
+    // knots, fixes whose derived speed is further than this from the reported SOG are
+    // dropped as position jumps, 0 disables the check
+    pub speed_mismatch_thr: f32,
+    pub allowed_lateness: i32, // seconds a record may arrive behind the newest one and still be reordered
+    pub on_late: LatePolicy, // drop, count or side (write to --late-output) records later than that
+}
+
+impl Default for PipelineConfig {
+    fn default() -> PipelineConfig {
+        PipelineConfig {
+            max_speed: 50.0,
+            rate: 10,
+            stop_speed_thr: 0.5,
+            distance_to_poi_thr: 1.0,
+            history_size: usize::MAX,
+            history_window: 0,
+            idle_timeout: 0,
+            flocks_distance_threshold: 0.3,
+            flocks_max_dt_threshold: 30 * 60,
+            flocks_max_bearing_threshold: 20.0,
+            comp_thr: 0.1,
+            opw_epsilon: 0.0003,
+            // libtorch is optional, builds without it predict with the ONNX export
+            predictor: if cfg!(feature = "torch") {
+                PredictorKind::Torch
+            } else {
+                PredictorKind::Onnx
+            },
+            model_path: "vrf_brest_proto_jit_trace.pth".to_string(),
+            onnx_model_path: "model.onnx".to_string(),
+            predict_batch_size: 1,
+            predict_batch_wait: 0.0,
+            predict_horizons: vec![0],
+            predict_step: 0,
+            on_error: ErrorPolicy::Abort,
+            prefer_reported: true,
+            speed_mismatch_thr: 0.0,
+            allowed_lateness: 0,
+            on_late: LatePolicy::Count,
+        }
+    }
+}
+
+impl PipelineConfig {
+    // Reads the config file (if any) and then applies MARSHAL_* environment overrides.
+    // Files ending in .json are parsed as JSON, everything else as TOML.
+    pub fn load(path: Option<&str>) -> Result<PipelineConfig, MarshalError> {
+        let mut cfg = match path {
+            Some(path) => PipelineConfig::from_file(path)?,
+            None => PipelineConfig::default(),
+        };
+        cfg.apply_env()?;
+        cfg.validate()?;
+        Ok(cfg)
+    }
+
+    // Rejects values the stages cannot work with, resample steps by `rate` seconds and the
+    // thresholds are distances, speeds and angles
+    pub fn validate(&self) -> Result<(), MarshalError> {
+        if self.rate <= 0 {
+            return Err(MarshalError::Config(format!(
+                "rate must be positive, got {}",
+                self.rate
+            )));
+        }
+        if self.history_size == 0 {
+            return Err(MarshalError::Config(
+                "history_size must be positive, got 0".to_string(),
+            ));
+        }
+        // seconds, 0 turns them off
+        let durations = [
+            ("history_window", self.history_window),
+            ("idle_timeout", self.idle_timeout),
+            ("allowed_lateness", self.allowed_lateness),
+        ];
+        for (name, value) in durations {
+            if value < 0 {
+                return Err(MarshalError::Config(format!(
+                    "{} must not be negative, got {}",
+                    name, value
+                )));
+            }
+        }
+        let thresholds = [
+            ("max_speed", self.max_speed),
+            ("stop_speed_thr", self.stop_speed_thr),
+            ("distance_to_poi_thr", self.distance_to_poi_thr),
+            ("flocks_distance_threshold", self.flocks_distance_threshold),
+            (
+                "flocks_max_dt_threshold",
+                self.flocks_max_dt_threshold as f32,
+            ),
+            (
+                "flocks_max_bearing_threshold",
+                self.flocks_max_bearing_threshold,
+            ),
+            ("comp_thr", self.comp_thr),
+            ("opw_epsilon", self.opw_epsilon),
+            ("speed_mismatch_thr", self.speed_mismatch_thr),
+        ];
+        for (name, value) in thresholds {
+            if value < 0.0 || value.is_nan() {
+                return Err(MarshalError::Config(format!(
+                    "{} must not be negative, got {}",
+                    name, value
+                )));
+            }
+        }
+        if self.predict_batch_size == 0 {
+            return Err(MarshalError::Config(
+                "predict_batch_size must be positive, got 0".to_string(),
+            ));
+        }
+        if !(self.predict_batch_wait >= 0.0 && self.predict_batch_wait.is_finite()) {
+            return Err(MarshalError::Config(format!(
+                "predict_batch_wait must be finite and not negative, got {}",
+                self.predict_batch_wait
+            )));
+        }
+        if self.predict_step < 0 {
+            return Err(MarshalError::Config(format!(
+                "predict_step must not be negative, got {}",
+                self.predict_step
+            )));
+        }
+        if let Some(horizon) = self.predict_horizons.iter().find(|horizon| **horizon < 0) {
+            return Err(MarshalError::Config(format!(
+                "predict_horizons must not be negative, got {}",
+                horizon
+            )));
+        }
+        Ok(())
+    }
+
+    // Seconds between the points a prediction is rolled out to
+    pub fn predict_interval(&self) -> i32 {
+        match self.predict_step {
+            0 => self.rate,
+            step => step,
+        }
+    }
+
+    pub fn from_file(path: &str) -> Result<PipelineConfig, MarshalError> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| MarshalError::Config(format!("cannot read '{}': {}", path, e)))?;
+
+        let is_json = Path::new(path)
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("json"));
+
+        if is_json {
+            serde_json::from_str(&contents)
+                .map_err(|e| MarshalError::Config(format!("invalid '{}': {}", path, e)))
+        } else {
+            toml::from_str(&contents)
+                .map_err(|e| MarshalError::Config(format!("invalid '{}': {}", path, e)))
+        }
+    }
+
+    pub fn apply_env(&mut self) -> Result<(), MarshalError> {
+        override_from_env(&mut self.max_speed, "MAX_SPEED")?;
+        override_from_env(&mut self.rate, "RATE")?;
+        override_from_env(&mut self.stop_speed_thr, "STOP_SPEED_THR")?;
+        override_from_env(&mut self.distance_to_poi_thr, "DISTANCE_TO_POI_THR")?;
+        override_from_env(&mut self.history_size, "HISTORY_SIZE")?;
+        override_from_env(&mut self.history_window, "HISTORY_WINDOW")?;
+        override_from_env(&mut self.idle_timeout, "IDLE_TIMEOUT")?;
+        override_from_env(
+            &mut self.flocks_distance_threshold,
+            "FLOCKS_DISTANCE_THRESHOLD",
+        )?;
+        override_from_env(&mut self.flocks_max_dt_threshold, "FLOCKS_MAX_DT_THRESHOLD")?;
+        override_from_env(
+            &mut self.flocks_max_bearing_threshold,
+            "FLOCKS_MAX_BEARING_THRESHOLD",
+        )?;
+        override_from_env(&mut self.comp_thr, "COMP_THR")?;
+        override_from_env(&mut self.opw_epsilon, "OPW_EPSILON")?;
+        override_from_env(&mut self.predictor, "PREDICTOR")?;
+        override_from_env(&mut self.model_path, "MODEL_PATH")?;
+        override_from_env(&mut self.onnx_model_path, "ONNX_MODEL_PATH")?;
+        override_from_env(&mut self.predict_batch_size, "PREDICT_BATCH_SIZE")?;
+        override_from_env(&mut self.predict_batch_wait, "PREDICT_BATCH_WAIT")?;
+        override_list_from_env(&mut self.predict_horizons, "PREDICT_HORIZONS")?;
+        override_from_env(&mut self.predict_step, "PREDICT_STEP")?;
+        override_from_env(&mut self.on_error, "ON_ERROR")?;
+        override_from_env(&mut self.prefer_reported, "PREFER_REPORTED")?;
+        override_from_env(&mut self.speed_mismatch_thr, "SPEED_MISMATCH_THR")?;
+        override_from_env(&mut self.allowed_lateness, "ALLOWED_LATENESS")?;
+        override_from_env(&mut self.on_late, "ON_LATE")?;
+        Ok(())
+    }
+}
+
+fn override_from_env<T: FromStr>(field: &mut T, name: &str) -> Result<(), MarshalError> {
+    let key = format!("{}{}", ENV_PREFIX, name);
+    if let Ok(value) = env::var(&key) {
+        *field = value
+            .parse()
+            .map_err(|_| MarshalError::Config(format!("cannot parse {}='{}'", key, value)))?;
+    }
+    Ok(())
+}
+
+// A comma separated list, e.g. MARSHAL_PREDICT_HORIZONS=300,600,1800
+fn override_list_from_env<T: FromStr>(field: &mut Vec<T>, name: &str) -> Result<(), MarshalError> {
+    let key = format!("{}{}", ENV_PREFIX, name);
+    if let Ok(value) = env::var(&key) {
+        *field = value
+            .split(',')
+            .map(|item| item.trim().parse())
+            .collect::<Result<_, _>>()
+            .map_err(|_| MarshalError::Config(format!("cannot parse {}='{}'", key, value)))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,158 @@
+use crate::streams::{Cleaned, Compressed, Resampled, StreamOperator};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Stage {
+    Clean,
+    Resample,
+    Compress,
+    Predict,
+}
+
+impl Stage {
+    pub const ALL: [Stage; 4] = [
+        Stage::Clean,
+        Stage::Resample,
+        Stage::Compress,
+        Stage::Predict,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Stage::Clean => "clean",
+            Stage::Resample => "resample",
+            Stage::Compress => "compress",
+            Stage::Predict => "predict",
+        }
+    }
+
+    // The operator behind a trajectory stage, predict has none
+    pub fn operator(&self) -> Option<Box<dyn StreamOperator>> {
+        match self {
+            Stage::Clean => Some(Box::new(Cleaned)),
+            Stage::Resample => Some(Box::new(Resampled)),
+            Stage::Compress => Some(Box::new(Compressed)),
+            Stage::Predict => None,
+        }
+    }
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for Stage {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Stage, String> {
+        Stage::ALL
+            .iter()
+            .find(|stage| stage.name() == s)
+            .copied()
+            .ok_or_else(|| {
+                format!(
+                    "unknown stage '{}' (expected clean, resample, compress or predict)",
+                    s
+                )
+            })
+    }
+}
+
+// Where a stage reads its records from: the raw input stream or the points emitted by another stage
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Input {
+    Raw,
+    Stage(Stage),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StageSpec {
+    pub stage: Stage,
+    pub input: Input,
+}
+
+impl fmt::Display for StageSpec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.input {
+            Input::Raw => write!(f, "{}", self.stage),
+            Input::Stage(input) => write!(f, "{}:{}", self.stage, input),
+        }
+    }
+}
+
+// "compress" reads the raw stream, "compress:clean" reads what clean emits.
+// predict defaults to the clean collection, no stage reads the points it predicts.
+impl FromStr for StageSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<StageSpec, String> {
+        let (stage, input) = match s.split_once(':') {
+            Some((stage, input)) => (stage.trim().parse()?, Some(input.trim())),
+            None => (s.trim().parse()?, None),
+        };
+
+        let input = match input {
+            None if stage == Stage::Predict => Input::Stage(Stage::Clean),
+            None | Some("raw") => Input::Raw,
+            Some(input) => Input::Stage(input.parse()?),
+        };
+
+        Ok(StageSpec { stage, input })
+    }
+}
+
+// Stages run in the order they are listed, so every input has to appear before its consumers
+#[derive(Debug, Clone)]
+pub struct Dataflow {
+    pub stages: Vec<StageSpec>,
+}
+
+impl Dataflow {
+    pub fn new(stages: Vec<StageSpec>) -> Result<Dataflow, String> {
+        for (i, spec) in stages.iter().enumerate() {
+            if stages[..i].iter().any(|other| other.stage == spec.stage) {
+                return Err(format!("stage '{}' is listed more than once", spec.stage));
+            }
+
+            match spec.input {
+                Input::Raw if spec.stage == Stage::Predict => {
+                    return Err("predict needs a trajectory stage as input".to_string())
+                }
+                Input::Stage(Stage::Predict) => {
+                    return Err(format!("'{}' cannot read from predict", spec))
+                }
+                Input::Stage(input) if !stages[..i].iter().any(|other| other.stage == input) => {
+                    return Err(format!(
+                        "'{}' reads from '{}' which is not listed before it",
+                        spec, input
+                    ));
+                }
+                _ => (),
+            }
+        }
+
+        Ok(Dataflow { stages })
+    }
+
+    // The single stage `spec` of a dataflow, whose input stage runs elsewhere (e.g. on
+    // another thread) and hands its points over with Pipeline::push_after
+    pub fn part(spec: StageSpec) -> Dataflow {
+        Dataflow { stages: vec![spec] }
+    }
+
+    pub fn contains(&self, stage: Stage) -> bool {
+        self.stages.iter().any(|spec| spec.stage == stage)
+    }
+}
+
+impl fmt::Display for Dataflow {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let specs: Vec<String> = self.stages.iter().map(|spec| spec.to_string()).collect();
+        write!(f, "{}", specs.join(","))
+    }
+}
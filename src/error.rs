@@ -0,0 +1,130 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug)]
+pub enum MarshalError {
+    Io(std::io::Error),
+    Csv(csv::Error),
+    Config(String),
+    Projection(String),
+    #[cfg(feature = "torch")]
+    Model(tch::TchError),
+    Onnx(tract_onnx::prelude::TractError),
+    Arrow(arrow::error::ArrowError),
+    Parquet(parquet::errors::ParquetError),
+    Checkpoint(bincode::Error),
+    InvalidRecord(String),
+}
+
+impl fmt::Display for MarshalError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MarshalError::Io(e) => write!(f, "io error: {}", e),
+            MarshalError::Csv(e) => write!(f, "csv error: {}", e),
+            MarshalError::Config(e) => write!(f, "config error: {}", e),
+            MarshalError::Projection(e) => write!(f, "projection error: {}", e),
+            #[cfg(feature = "torch")]
+            MarshalError::Model(e) => write!(f, "model error: {}", e),
+            MarshalError::Onnx(e) => write!(f, "onnx model error: {:#}", e),
+            MarshalError::Arrow(e) => write!(f, "arrow error: {}", e),
+            MarshalError::Parquet(e) => write!(f, "parquet error: {}", e),
+            MarshalError::Checkpoint(e) => write!(f, "checkpoint error: {}", e),
+            MarshalError::InvalidRecord(e) => write!(f, "invalid record: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for MarshalError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MarshalError::Io(e) => Some(e),
+            MarshalError::Csv(e) => Some(e),
+            #[cfg(feature = "torch")]
+            MarshalError::Model(e) => Some(e),
+            MarshalError::Onnx(e) => Some(e.as_ref()),
+            MarshalError::Arrow(e) => Some(e),
+            MarshalError::Parquet(e) => Some(e),
+            MarshalError::Checkpoint(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for MarshalError {
+    fn from(e: std::io::Error) -> MarshalError {
+        MarshalError::Io(e)
+    }
+}
+
+impl From<csv::Error> for MarshalError {
+    fn from(e: csv::Error) -> MarshalError {
+        MarshalError::Csv(e)
+    }
+}
+
+#[cfg(feature = "torch")]
+impl From<tch::TchError> for MarshalError {
+    fn from(e: tch::TchError) -> MarshalError {
+        MarshalError::Model(e)
+    }
+}
+
+impl From<tract_onnx::prelude::TractError> for MarshalError {
+    fn from(e: tract_onnx::prelude::TractError) -> MarshalError {
+        MarshalError::Onnx(e)
+    }
+}
+
+impl From<arrow::error::ArrowError> for MarshalError {
+    fn from(e: arrow::error::ArrowError) -> MarshalError {
+        MarshalError::Arrow(e)
+    }
+}
+
+impl From<parquet::errors::ParquetError> for MarshalError {
+    fn from(e: parquet::errors::ParquetError) -> MarshalError {
+        MarshalError::Parquet(e)
+    }
+}
+
+impl From<bincode::Error> for MarshalError {
+    fn from(e: bincode::Error) -> MarshalError {
+        MarshalError::Checkpoint(e)
+    }
+}
+
+impl From<proj::ProjCreateError> for MarshalError {
+    fn from(e: proj::ProjCreateError) -> MarshalError {
+        MarshalError::Projection(e.to_string())
+    }
+}
+
+impl From<proj::ProjError> for MarshalError {
+    fn from(e: proj::ProjError) -> MarshalError {
+        MarshalError::Projection(e.to_string())
+    }
+}
+
+// What the driver does when a single record cannot be read or processed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ErrorPolicy {
+    Abort,
+    Skip,
+}
+
+impl FromStr for ErrorPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ErrorPolicy, String> {
+        match s {
+            "abort" => Ok(ErrorPolicy::Abort),
+            "skip" => Ok(ErrorPolicy::Skip),
+            _ => Err(format!(
+                "unknown error policy '{}' (expected abort or skip)",
+                s
+            )),
+        }
+    }
+}
@@ -0,0 +1,114 @@
+use crate::error::MarshalError;
+use crate::predictor::Predictor;
+use crate::structs::{Coordinate, Trajectory, PREDICT_WINDOW};
+
+// Baselines that extrapolate the motion of an object, no model needed. Like the network
+// they predict the next point, taken to come as long after the last one as the last one
+// came after the point before it
+
+// Index of the last point and the seconds since the point before, None until the last
+// point has a speed
+fn last_step(traj: &Trajectory) -> Option<(usize, i32)> {
+    let last = traj.timestamps.len().checked_sub(1)?;
+    if last == 0 || traj.speed[last] < 0.0 {
+        return None;
+    }
+    Some((last, traj.timestamps[last] - traj.timestamps[last - 1]))
+}
+
+// Keeps the speed and bearing of the last point
+pub struct DeadReckoning;
+
+impl Predictor for DeadReckoning {
+    fn predict(&self, traj: &Trajectory) -> Result<Option<Coordinate>, MarshalError> {
+        Ok(last_step(traj).map(|(last, dt)| {
+            traj.coordinates[last].extrapolate(traj.speed[last], traj.bearing[last], dt)
+        }))
+    }
+}
+
+// Constant turn rate and velocity: keeps the speed of the last point and turns as much as
+// the bearing turned over the last step
+pub struct Ctrv;
+
+impl Predictor for Ctrv {
+    fn predict(&self, traj: &Trajectory) -> Result<Option<Coordinate>, MarshalError> {
+        let (last, dt) = match last_step(traj) {
+            Some((last, dt)) if traj.speed[last - 1] >= 0.0 => (last, dt),
+            _ => return Ok(None),
+        };
+        // -180..180
+        let turn = (traj.bearing[last] - traj.bearing[last - 1] + 540.0) % 360.0 - 180.0;
+
+        // the chord of the arc, shorter than the arc and half-way through the turn
+        let half = (turn / 2.0).to_radians();
+        let chord = if half.abs() > 1e-6 {
+            half.sin() / half
+        } else {
+            1.0
+        };
+        Ok(Some(traj.coordinates[last].extrapolate(
+            traj.speed[last] * chord,
+            traj.bearing[last] + turn / 2.0,
+            dt,
+        )))
+    }
+}
+
+// Least-squares lines of longitude and latitude over time, fitted to the last
+// PREDICT_WINDOW points (the ones the network sees)
+pub struct Regression;
+
+impl Predictor for Regression {
+    fn predict(&self, traj: &Trajectory) -> Result<Option<Coordinate>, MarshalError> {
+        let n = traj.timestamps.len();
+        if n < 2 {
+            return Ok(None);
+        }
+        let from = n.saturating_sub(PREDICT_WINDOW);
+        let origin = &traj.coordinates[n - 1];
+        let now = traj.timestamps[n - 1];
+        let dt = now - traj.timestamps[n - 2];
+
+        // relative to the last point, longitudes unwrapped around it (in f64, f32 only
+        // resolves some 7 metres around 540)
+        let points: Vec<(f64, f64, f64)> = traj.coordinates[from..]
+            .iter()
+            .zip(traj.timestamps[from..].iter())
+            .map(|(coord, t)| {
+                (
+                    (t - now) as f64,
+                    (coord.x as f64 - origin.x as f64 + 540.0) % 360.0 - 180.0,
+                    coord.y as f64 - origin.y as f64,
+                )
+            })
+            .collect();
+
+        let k = points.len() as f64;
+        let mean_t = points.iter().map(|p| p.0).sum::<f64>() / k;
+        let mean_x = points.iter().map(|p| p.1).sum::<f64>() / k;
+        let mean_y = points.iter().map(|p| p.2).sum::<f64>() / k;
+        let var_t: f64 = points.iter().map(|p| (p.0 - mean_t).powi(2)).sum();
+        if var_t == 0.0 {
+            return Ok(None);
+        }
+        let slope_x = points
+            .iter()
+            .map(|p| (p.0 - mean_t) * (p.1 - mean_x))
+            .sum::<f64>()
+            / var_t;
+        let slope_y = points
+            .iter()
+            .map(|p| (p.0 - mean_t) * (p.2 - mean_y))
+            .sum::<f64>()
+            / var_t;
+
+        let ahead = dt as f64 - mean_t;
+        let x = origin.x as f64 + mean_x + slope_x * ahead;
+        let y = origin.y as f64 + mean_y + slope_y * ahead;
+        Ok(Some(Coordinate {
+            x: ((x + 540.0) % 360.0 - 180.0) as f32,
+            y: y.clamp(-90.0, 90.0) as f32,
+        }))
+    }
+}
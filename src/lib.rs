@@ -0,0 +1,39 @@
+//! Mobility analytics pipeline: cleaning, resampling, compression and prediction
+//! of streaming trajectories, e.g. AIS vessel positions.
+
+pub mod checkpoint;
+pub mod columnar;
+pub mod config;
+pub mod dataflow;
+pub mod error;
+pub mod kinematic;
+pub mod network;
+pub mod nmea;
+pub mod pipeline;
+pub mod predictor;
+pub mod reorder;
+pub mod replay;
+pub mod report;
+pub mod sharded;
+pub mod sinks;
+pub mod sources;
+pub mod staged;
+pub mod stats;
+pub mod streams;
+pub mod structs;
+pub mod workers;
+
+pub use checkpoint::Checkpoint;
+pub use config::PipelineConfig;
+pub use dataflow::{Dataflow, Input, Stage, StageSpec};
+pub use error::{ErrorPolicy, MarshalError};
+pub use pipeline::{EvictionClock, Lag, Pipeline, PipelineState, Volume};
+pub use predictor::{Predictor, PredictorKind};
+pub use report::Report;
+pub use sharded::ShardedPipeline;
+pub use sinks::{Sink, Sinks};
+pub use staged::StagedPipeline;
+pub use stats::{Latency, QueueStats, StateSize};
+pub use streams::{Emitted, StreamOperator};
+pub use structs::{Coordinate, Pois, Record, TrajCollection, Trajectory};
+pub use workers::Workers;
@@ -1,115 +1,455 @@
-mod streams;
-mod structs;
-use core::time;
-use kdam::tqdm;
-use std::collections::hash_map::Entry;
-use std::collections::HashMap;
-use std::time::Instant;
-use streams::{cleaned, compressed, resampled};
-use structs::{Coordinate, Pois, Record, TrajCollection, Trajectory};
-use tch;
+mod cli;
+use clap::Parser;
+use cli::{Cli, Command, ReportFormat, RunArgs};
+use kdam::{tqdm, BarExt};
+use marshal::{
+    checkpoint::{self, Checkpoint},
+    network, predictor,
+    reorder::Reordered,
+    replay::{replay, ReplayClock},
+    report::{Dataset, Report},
+    sharded::ShardedPipeline,
+    sinks::{self, open_sink, resume_sink},
+    sources::open_source,
+    staged::StagedPipeline,
+    workers::{Outputs, WorkerOutput},
+    Dataflow, ErrorPolicy, MarshalError, Pipeline, PipelineConfig, Pois, PredictorKind, Sink,
+    Sinks, Stage, Trajectory, Workers,
+};
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::SystemTime;
 
 // use std::{thread, time};
 
-static MAX_SPEED: f32 = 50.0; // knots
-static RATE: i32 = 10; // seconds
-static STOP_SPEED_THR: f32 = 0.5; // knots
-static DISTANCE_TO_POI_THR: f32 = 1.0; // nmiles
-static HISTORY_SIZE: usize = usize::MAX; // how many records should I keep in mem
-static FLOCKS_DISTANCE_THRESHOLD: f32 = 0.3; // nmiles
-static FLOCKS_MAX_DT_THRESHOLD: i32 = 30 * 60; // seconds
-static FLOCKS_MAX_BEARING_THRESHOLD: f32 = 20.0;
-static COMP_THR: f32 = 0.1;
-static OPW_EPSILON: f32 = 0.0003;
-static MODEL_PATH: &str = "vrf_brest_proto_jit_trace.pth";
-
-fn run(path: &str, pois_path: &str) -> Result<(), csv::Error> {
+fn run(args: &RunArgs, cfg: &PipelineConfig, dataflow: &Dataflow) -> Result<Report, MarshalError> {
+    let started = SystemTime::now();
+
     // let mut reader_traj = csv::Reader::from_path(env!("CRDS"))?;
 
-    let mut reader_traj = csv::Reader::from_path(path)?;
+    if (args.threads > 1 || args.pipelined) && args.checkpoint.is_some() {
+        return Err(MarshalError::Config(
+            "checkpoints need a single pipeline, drop --threads/--pipelined or --checkpoint"
+                .to_string(),
+        ));
+    }
+    // a resumed run appends to the sinks
+    if args.checkpoint.is_some() {
+        for path in args
+            .sinks
+            .iter()
+            .map(|spec| &spec.path)
+            .chain(args.evicted_sink.iter())
+        {
+            sinks::appendable(path)?;
+        }
+    }
 
-    let pois: Pois = Pois::new_from_path(pois_path);
+    let resumed = match &args.checkpoint {
+        Some(path) if args.resume && Path::new(path).exists() => {
+            let resumed = Checkpoint::load(path)?;
+            resumed.check(&args.input, dataflow)?;
+            Some(resumed)
+        }
+        _ => None,
+    };
+
+    let mut source = open_source(&args.input, &args.columns)?;
+    // a live input cannot be rewound, it continues with whatever arrives now
+    if let (Some(resumed), false) = (&resumed, network::is_network(&args.input)) {
+        checkpoint::skip(&mut source, resumed.offset)?;
+    }
+    let clock = args.replay.map(ReplayClock::new);
+    let source = match &clock {
+        Some(clock) => replay(source, clock.clone()),
+        None => source,
+    };
+    let mut reordered = Reordered::new(
+        &args.input,
+        source,
+        cfg.allowed_lateness,
+        cfg.on_late,
+        args.late_output.as_deref(),
+    )?;
 
-    let model = tch::CModule::load(MODEL_PATH).unwrap();
+    let pois: Pois = Pois::new_from_path(&args.pois)?;
 
     // pois.pretty();
     // println!("oid\tlon\tlat\tspeed\tbearing\tstoped\ttrip\ttimestamp\tpoi_id\tgps");
 
-    let mut traj_clean = TrajCollection {
-        object: HashMap::new(),
+    // --threads splits the objects over that many pipelines, each with a share of the
+    // warm-up (the first ones take the remainder), --pipelined the stages
+    let threads = args.threads.max(1);
+    let dataflows = if args.pipelined {
+        dataflow
+            .stages
+            .iter()
+            .map(|spec| Dataflow::part(*spec))
+            .collect()
+    } else {
+        vec![dataflow.clone(); threads]
     };
-    let mut traj_resed = TrajCollection {
-        object: HashMap::new(),
+    let mut pipelines = vec![];
+    for (i, part) in dataflows.into_iter().enumerate() {
+        let mut pipeline = Pipeline::new(part, cfg.clone(), pois.clone())?;
+        pipeline.warmup = args.warmup / threads + (i < args.warmup % threads) as usize;
+        pipeline.state_every = args.state_every;
+        pipeline.keep_evicted = args.evicted_sink.is_some();
+        pipelines.push(pipeline);
+    }
+    let mut engine = if args.pipelined {
+        Engine::Threaded(Box::new(StagedPipeline::new(pipelines, args.queue_size)))
+    } else if threads > 1 {
+        Engine::Threaded(Box::new(ShardedPipeline::new(pipelines, args.queue_size)))
+    } else {
+        Engine::Sequential(Box::new(pipelines.pop().unwrap()))
     };
-    let mut traj_comp = TrajCollection {
-        object: HashMap::new(),
+
+    // nothing wakes the main thread before the next record
+    if let (Engine::Sequential(_), true) = (&engine, network::is_network(&args.input)) {
+        if cfg.predict_batch_size > 1 && cfg.predict_batch_wait > 0.0 {
+            eprintln!(
+                "{}: a single pipeline runs an overdue prediction batch with the next record, \
+                 --threads or --pipelined keep to predict_batch_wait",
+                args.input
+            );
+        }
+    }
+
+    let (mut sinks, mut evicted_sink) = match &resumed {
+        Some(resumed) => (
+            Sinks::resume(&args.sinks, &resumed.sinks)?,
+            match &args.evicted_sink {
+                Some(path) => Some(resume_sink(path, &resumed.sinks)?),
+                None => None,
+            },
+        ),
+        None => (
+            Sinks::open(&args.sinks, args.geometry)?,
+            match &args.evicted_sink {
+                Some(path) => Some(open_sink(path, args.geometry)?),
+                None => None,
+            },
+        ),
     };
+    if let Some(resumed) = resumed {
+        eprintln!("{}: resuming after {} records", args.input, resumed.offset);
+        reordered.resume(resumed.offset, resumed.reorder.into_owned());
+        if let Engine::Sequential(pipeline) = &mut engine {
+            pipeline.restore(resumed.pipeline)?;
+        }
+    }
+    let mut skipped = 0;
+    let mut failed = HashSet::new(); // records a worker failed, see WorkerOutput
 
-    let mut cnt_clean = 0.0;
-    let mut cnt_pred = 0.0;
-    let mut cnt_resed = 0.0;
-    let mut cnt_comp = 0.0;
+    // kdam still draws the final bar when disabled, so skip the bar altogether
+    let mut bar = (!args.quiet).then(|| tqdm!());
+    let mut released = 0;
 
-    for record in tqdm!(reader_traj.deserialize(),) {
+    // not a for loop, the reorder buffer goes into the checkpoints
+    while let Some(record) = reordered.next() {
         // for record in tqdm!(reader.deserialize()) {
-        let record: Record = record?;
+        let outcome = record.and_then(|record| {
+            let due = clock.as_ref().map(|clock| clock.due(record.t));
+            match &mut engine {
+                Engine::Sequential(pipeline) => {
+                    let emitted = match due {
+                        Some(due) => pipeline.push_due(record, due)?,
+                        None => pipeline.push(record)?,
+                    };
+                    let evicted = pipeline.take_evicted();
+                    write(&mut sinks, &mut evicted_sink, &emitted, &evicted)
+                }
+                // the outputs are written once the workers are done with the record
+                Engine::Threaded(workers) => {
+                    workers.push(record, due);
+                    Ok(())
+                }
+            }
+        });
+        tolerate(outcome, cfg.on_error, &mut skipped)?;
+
+        if let Engine::Threaded(workers) = &engine {
+            for output in workers.ready() {
+                if let Some(outcome) = received(output, &mut failed) {
+                    let outcome = outcome.and_then(|(emitted, evicted)| {
+                        write(&mut sinks, &mut evicted_sink, &emitted, &evicted)
+                    });
+                    tolerate(outcome, cfg.on_error, &mut skipped)?;
+                }
+            }
+        }
 
-        let now = Instant::now();
+        if let Some(bar) = bar.as_mut() {
+            let _ = bar.update(1);
+        }
+        released += 1;
+        if let (Some(path), Engine::Sequential(pipeline)) = (&args.checkpoint, &mut engine) {
+            if args.checkpoint_every > 0 && released % args.checkpoint_every == 0 {
+                // a prediction batch is not part of the checkpoint
+                let outcome = pipeline
+                    .flush()
+                    .and_then(|emitted| write(&mut sinks, &mut evicted_sink, &emitted, &[]));
+                tolerate(outcome, cfg.on_error, &mut skipped)?;
+                let outputs = (&mut sinks, &mut evicted_sink);
+                save_checkpoint(path, args, dataflow, &reordered, pipeline, outputs)?;
+            }
+        }
+    }
+    if let Some(bar) = bar.as_mut() {
+        let _ = bar.refresh();
+    }
 
-        let clean_traj = cleaned(record.clone(), &traj_clean, &pois);
+    let pipeline = match engine {
+        Engine::Sequential(mut pipeline) => {
+            let outcome = pipeline
+                .flush()
+                .and_then(|emitted| write(&mut sinks, &mut evicted_sink, &emitted, &[]));
+            tolerate(outcome, cfg.on_error, &mut skipped)?;
+            *pipeline
+        }
+        // the workers flush their pipelines when the input closes
+        Engine::Threaded(mut workers) => {
+            for output in workers.close() {
+                if let Some(outcome) = received(output, &mut failed) {
+                    let outcome = outcome.and_then(|(emitted, evicted)| {
+                        write(&mut sinks, &mut evicted_sink, &emitted, &evicted)
+                    });
+                    tolerate(outcome, cfg.on_error, &mut skipped)?;
+                }
+            }
+            workers.join()
+        }
+    };
 
-        traj_clean.extend_flush(clean_traj, None);
+    let throughput = pipeline.throughput();
+    if let Some(path) = &args.checkpoint {
+        let outputs = (&mut sinks, &mut evicted_sink);
+        save_checkpoint(path, args, dataflow, &reordered, &pipeline, outputs)?;
+    }
+    sinks.finish()?;
+    if let Some(sink) = evicted_sink.as_mut() {
+        sink.finish()?;
+    }
 
-        cnt_clean += now.elapsed().as_nanos() as f64;
+    if skipped > 0 {
+        eprintln!("{}: skipped {} records", args.input, skipped);
+    }
 
-        // ------------
+    if let Some(clock) = &clock {
+        // a last lag well above the mean means the stage could not keep up until the end
+        for spec in dataflow.stages.iter() {
+            if let Some(lag) = pipeline.lags.get(&spec.stage) {
+                eprintln!(
+                    "{} lag at {}x: mean {:.2}ms, max {:.2}ms, last {:.2}ms",
+                    spec.stage,
+                    clock.speedup(),
+                    lag.mean() * 1000.0,
+                    lag.max * 1000.0,
+                    lag.last * 1000.0
+                );
+            }
+        }
+    }
 
-        let now = Instant::now();
+    let dataset = Dataset::new(&args.input, pipeline.records, args.warmup, skipped);
+    Ok(Report::new(&pipeline, dataset, started, throughput))
+}
 
-        let resampled_traj = resampled(record.clone(), &traj_resed, &pois);
-        traj_resed.extend_flush(resampled_traj, None);
+// One pipeline on this thread, or several on worker threads
+enum Engine {
+    Sequential(Box<Pipeline>),
+    Threaded(Box<dyn Workers>),
+}
 
-        cnt_resed += now.elapsed().as_nanos() as f64;
+fn write(
+    sinks: &mut Sinks,
+    evicted_sink: &mut Option<Box<dyn Sink>>,
+    emitted: &[(Stage, Trajectory)],
+    evicted: &[(Stage, Trajectory)],
+) -> Result<(), MarshalError> {
+    sinks.write(emitted)?;
+    if let Some(sink) = evicted_sink.as_mut() {
+        for (stage, traj) in evicted.iter() {
+            sink.write(*stage, traj)?;
+        }
+    }
+    Ok(())
+}
 
-        // ------------
+// What a worker sent back, None for a record another worker failed already
+fn received(
+    output: WorkerOutput,
+    failed: &mut HashSet<usize>,
+) -> Option<Result<Outputs, MarshalError>> {
+    match output {
+        Ok(outputs) => Some(Ok(outputs)),
+        Err((Some(index), _)) if !failed.insert(index) => None,
+        Err((_, e)) => Some(Err(e)),
+    }
+}
 
-        let now = Instant::now();
+// Applies the error policy to the outcome of a record
+fn tolerate(
+    outcome: Result<(), MarshalError>,
+    policy: ErrorPolicy,
+    skipped: &mut usize,
+) -> Result<(), MarshalError> {
+    match (outcome, policy) {
+        (Err(e), ErrorPolicy::Abort) => Err(e),
+        (Err(_), ErrorPolicy::Skip) => {
+            *skipped += 1;
+            Ok(())
+        }
+        (Ok(()), _) => Ok(()),
+    }
+}
 
-        let (compressed_traj, flush_id) = compressed(record.clone(), &traj_comp, &pois);
+fn save_checkpoint(
+    path: &str,
+    args: &RunArgs,
+    dataflow: &Dataflow,
+    reordered: &Reordered,
+    pipeline: &Pipeline,
+    (sinks, evicted_sink): (&mut Sinks, &mut Option<Box<dyn Sink>>),
+) -> Result<(), MarshalError> {
+    let mut lengths = sinks.checkpoint()?;
+    if let (Some(path), Some(sink)) = (&args.evicted_sink, evicted_sink.as_mut()) {
+        match sink.checkpoint()? {
+            Some(length) => lengths.push((path.clone(), length)),
+            None => sinks::appendable(path)?,
+        }
+    }
+    Checkpoint {
+        input: args.input.clone(),
+        dataflow: dataflow.to_string(),
+        offset: reordered.consumed(),
+        reorder: Cow::Borrowed(reordered.buffer()),
+        pipeline: pipeline.state(),
+        sinks: lengths,
+    }
+    .save(path)
+}
 
-        traj_comp.extend_flush(compressed_traj, flush_id);
+fn validate(
+    args: &RunArgs,
+    cfg: &PipelineConfig,
+    dataflow: &Dataflow,
+) -> Result<String, MarshalError> {
+    let pois = Pois::new_from_path(&args.pois)?;
 
-        cnt_comp += now.elapsed().as_nanos() as f64;
+    if dataflow.contains(Stage::Predict) {
+        predictor::load(cfg)?;
+    }
 
-        // ------------
+    let source = open_source(&args.input, &args.columns)?;
+    let mut records = 0;
+    let mut oids = HashSet::new();
 
-        let now = Instant::now();
+    for record in source {
+        let record = record?;
+        record.validate()?;
+        oids.insert(record.oid);
+        records += 1;
+    }
 
-        traj_clean.predict_for_oid(record.oid, &model);
+    Ok(format!(
+        "{} -> {} records, {} objects, {} pois",
+        args.input,
+        records,
+        oids.len(),
+        pois.pois.len()
+    ))
+}
 
-        cnt_pred += now.elapsed().as_nanos() as f64;
+fn load_config(args: &RunArgs) -> Result<PipelineConfig, MarshalError> {
+    // the config file is read first, MARSHAL_<PARAM> env vars and then --predictor and
+    // --model override it
+    let mut cfg = PipelineConfig::load(args.config.as_deref())?;
+    if let Some(predictor) = args.predictor {
+        cfg.predictor = predictor;
+    }
+    if let Some(model) = &args.model {
+        match cfg.predictor {
+            PredictorKind::Torch => cfg.model_path = model.clone(),
+            PredictorKind::Onnx => cfg.onnx_model_path = model.clone(),
+            // the kinematic baselines have no model
+            _ => {
+                return Err(MarshalError::Config(
+                    "--model only applies to the torch and onnx predictors".to_string(),
+                ))
+            }
+        }
     }
+    Ok(cfg)
+}
 
-    println!(
-        "{} -> {},{},{},{}",
-        path,
-        cnt_clean / 10_000.0,
-        cnt_resed / 10_000.0,
-        cnt_comp / 10_000.0,
-        cnt_pred / 10_000.0
-    );
-    Ok(())
+fn open_output(args: &RunArgs) -> io::Result<Box<dyn Write>> {
+    match &args.output {
+        Some(path) => Ok(Box::new(File::create(path)?)),
+        None => Ok(Box::new(io::stdout())),
+    }
 }
 
 fn main() {
-    // run("mt.csv", "ports_saronikos.csv");
-    eprintln!("{:?}", run("brest.csv", "ports_brest.csv"));
-}
+    let cli = Cli::parse();
+
+    let args = match &cli.command {
+        Command::Run(args) | Command::Validate(args) => args,
+        Command::Bench { run, .. } => run,
+    };
+
+    let cfg = match load_config(args) {
+        Ok(cfg) => cfg,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let dataflow = match Dataflow::new(args.stages.clone()) {
+        Ok(dataflow) => dataflow,
+        Err(e) => {
+            eprintln!("invalid --stages: {}", e);
+            std::process::exit(1);
+        }
+    };
 
-// let (processed_trajectory, flush_id) = if STREAM_ID=="1" {
-//     (resampled(record.clone(), &traj, &pois),None)
-// } else if STREAM_ID=="2" {
-//     compressed(record.clone(), &traj, &pois)
-// } else {
-//     panic!("SID '{}' is not available", STREAM_ID)
-// };
+    let mut output = match open_output(args) {
+        Ok(output) => output,
+        Err(e) => {
+            eprintln!("cannot open output: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let iterations = match &cli.command {
+        Command::Bench { repeat, .. } => *repeat,
+        _ => 1,
+    };
+
+    for _ in 0..iterations {
+        // a live input was interrupted, the remaining repetitions would be empty
+        if network::shutdown_requested() {
+            break;
+        }
+        let report = match &cli.command {
+            Command::Validate(_) => validate(args, &cfg, &dataflow),
+            _ => run(args, &cfg, &dataflow).map(|report| match args.format {
+                ReportFormat::Text => report.to_string(),
+                // one object per line, so bench iterations can be appended and aggregated
+                ReportFormat::Json => serde_json::to_string(&report).unwrap(),
+            }),
+        };
+        match report {
+            Ok(line) => writeln!(output, "{}", line).unwrap(),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+}
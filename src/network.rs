@@ -0,0 +1,374 @@
+use crate::error::MarshalError;
+use crate::nmea::NmeaDecoder;
+use crate::sources::{ColumnMapping, RecordSource};
+use crate::structs::Record;
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, ErrorKind};
+use std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// How often blocked listener threads and the source look at their stop flags
+static POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+static SHUTDOWN: OnceLock<Arc<AtomicBool>> = OnceLock::new();
+
+// Set by the first SIGINT/SIGTERM once a network source has been opened, a second one
+// kills the process as usual
+fn shutdown_flag() -> Arc<AtomicBool> {
+    SHUTDOWN
+        .get_or_init(|| {
+            let flag = Arc::new(AtomicBool::new(false));
+            for signal in [signal_hook::consts::SIGINT, signal_hook::consts::SIGTERM] {
+                // the order matters: the conditional shutdown sees the flag before it is set
+                let _ = signal_hook::flag::register_conditional_shutdown(signal, 1, flag.clone());
+                let _ = signal_hook::flag::register(signal, flag.clone());
+            }
+            flag
+        })
+        .clone()
+}
+
+pub fn shutdown_requested() -> bool {
+    SHUTDOWN
+        .get()
+        .is_some_and(|flag| flag.load(Ordering::Relaxed))
+}
+
+// tcp://0.0.0.0:5000 or udp://0.0.0.0:5000, optionally followed by ?idle=<seconds> to end
+// the stream once nothing has been received for that long
+pub fn is_network(input: &str) -> bool {
+    input.starts_with("tcp://") || input.starts_with("udp://")
+}
+
+// Listens for CSV lines or NMEA sentences and yields their records until SIGINT/SIGTERM or
+// the idle timeout. Every peer has its own parser: a CSV header line (anything whose first
+// field is not a number) sets the column order of the lines that follow it, oid,t,lon,lat
+// is assumed until then.
+pub fn network_source(url: &str, mapping: &ColumnMapping) -> Result<RecordSource, MarshalError> {
+    Ok(Box::new(NetworkSource::bind(url, mapping)?))
+}
+
+// Updated by the listener threads
+#[derive(Default)]
+struct Stats {
+    lines: AtomicUsize,
+    bytes: AtomicUsize,
+}
+
+fn accept(
+    listener: TcpListener,
+    sender: Sender<(SocketAddr, String)>,
+    stats: Arc<Stats>,
+    stop: Arc<AtomicBool>,
+) {
+    while !stop.load(Ordering::Relaxed) {
+        match listener.accept() {
+            Ok((stream, peer)) => {
+                let (sender, stats, stop) = (sender.clone(), stats.clone(), stop.clone());
+                thread::spawn(move || read_stream(stream, peer, sender, stats, stop));
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => thread::sleep(POLL_INTERVAL),
+            Err(e) => {
+                eprintln!("accept failed: {}", e);
+                thread::sleep(POLL_INTERVAL);
+            }
+        }
+    }
+}
+
+fn read_stream(
+    stream: TcpStream,
+    peer: SocketAddr,
+    sender: Sender<(SocketAddr, String)>,
+    stats: Arc<Stats>,
+    stop: Arc<AtomicBool>,
+) {
+    if stream.set_nonblocking(false).is_err()
+        || stream.set_read_timeout(Some(POLL_INTERVAL)).is_err()
+    {
+        return;
+    }
+    let mut reader = BufReader::new(stream);
+    // a timeout can hit in the middle of a line, what was read so far stays in the buffer
+    let mut line = vec![];
+
+    while !stop.load(Ordering::Relaxed) {
+        match reader.read_until(b'\n', &mut line) {
+            Ok(0) => break,
+            Ok(_) if line.ends_with(b"\n") => {
+                stats.lines.fetch_add(1, Ordering::Relaxed);
+                stats.bytes.fetch_add(line.len(), Ordering::Relaxed);
+                if sender
+                    .send((peer, String::from_utf8_lossy(&line).into_owned()))
+                    .is_err()
+                {
+                    break;
+                }
+                line.clear();
+            }
+            Ok(_) => {}
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+            Err(_) => break,
+        }
+    }
+
+    // the last line of a stream does not need a newline
+    if !line.is_empty() {
+        stats.lines.fetch_add(1, Ordering::Relaxed);
+        stats.bytes.fetch_add(line.len(), Ordering::Relaxed);
+        let _ = sender.send((peer, String::from_utf8_lossy(&line).into_owned()));
+    }
+}
+
+fn receive(
+    socket: UdpSocket,
+    sender: Sender<(SocketAddr, String)>,
+    stats: Arc<Stats>,
+    stop: Arc<AtomicBool>,
+) {
+    let mut buffer = [0u8; 65536];
+    while !stop.load(Ordering::Relaxed) {
+        match socket.recv_from(&mut buffer) {
+            Ok((size, peer)) => {
+                stats.bytes.fetch_add(size, Ordering::Relaxed);
+                // a datagram may hold several lines
+                for line in String::from_utf8_lossy(&buffer[..size]).lines() {
+                    stats.lines.fetch_add(1, Ordering::Relaxed);
+                    if sender.send((peer, line.to_string())).is_err() {
+                        return;
+                    }
+                }
+            }
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+            Err(e) => eprintln!("receive failed: {}", e),
+        }
+    }
+}
+
+// The state of a single peer
+struct LineParser {
+    header: csv::StringRecord,
+    nmea: NmeaDecoder,
+}
+
+impl LineParser {
+    fn new() -> LineParser {
+        LineParser {
+            header: csv::StringRecord::from(vec!["oid", "t", "lon", "lat"]),
+            nmea: NmeaDecoder::new(),
+        }
+    }
+
+    fn parse(
+        &mut self,
+        line: &str,
+        mapping: &ColumnMapping,
+    ) -> Option<Result<Record, MarshalError>> {
+        let line = line.trim();
+        if line.is_empty() {
+            return None;
+        }
+        if line.contains("VDM,") || line.contains("VDO,") {
+            return self.nmea.decode(line);
+        }
+
+        let fields = match csv::ReaderBuilder::new()
+            .has_headers(false)
+            .from_reader(line.as_bytes())
+            .records()
+            .next()?
+        {
+            Ok(fields) => fields,
+            Err(e) => return Some(Err(e.into())),
+        };
+
+        if fields
+            .get(0)
+            .is_none_or(|first| first.trim().parse::<f64>().is_err())
+        {
+            self.header = mapping.rename(&fields);
+            return None;
+        }
+        Some(
+            fields
+                .deserialize(Some(&self.header))
+                .map_err(MarshalError::from),
+        )
+    }
+}
+
+pub struct NetworkSource {
+    url: String,
+    address: SocketAddr, // the one bound, e.g. the port picked for :0
+    mapping: ColumnMapping,
+    lines: Receiver<(SocketAddr, String)>,
+    parsers: HashMap<SocketAddr, LineParser>,
+    stats: Arc<Stats>,
+    stop: Arc<AtomicBool>,
+    shutdown: Arc<AtomicBool>,
+    idle: Option<Duration>,
+    started: Instant,
+    last_line: Instant,
+    records: usize,
+    errors: usize,
+    finished: bool,
+}
+
+impl NetworkSource {
+    pub fn bind(url: &str, mapping: &ColumnMapping) -> Result<NetworkSource, MarshalError> {
+        let (scheme, rest) = url.split_once("://").unwrap_or(("", url));
+        let (address, query) = rest.split_once('?').unwrap_or((rest, ""));
+
+        let mut idle = None;
+        for param in query.split('&').filter(|param| !param.is_empty()) {
+            match param.split_once('=') {
+                Some(("idle", seconds)) => {
+                    // negative, NaN and infinite timeouts parse, but are no durations
+                    let invalid =
+                        || MarshalError::Config(format!("invalid idle timeout in '{}'", url));
+                    let seconds: f64 = seconds.parse().map_err(|_| invalid())?;
+                    idle = Some(Duration::try_from_secs_f64(seconds).map_err(|_| invalid())?);
+                }
+                _ => {
+                    return Err(MarshalError::Config(format!(
+                        "unknown parameter '{}' in '{}' (expected idle=<seconds>)",
+                        param, url
+                    )))
+                }
+            }
+        }
+
+        let stats = Arc::new(Stats::default());
+        let stop = Arc::new(AtomicBool::new(false));
+        let (sender, lines) = mpsc::channel();
+
+        let address = match scheme {
+            "tcp" => {
+                let listener = TcpListener::bind(address)?;
+                listener.set_nonblocking(true)?;
+                let address = listener.local_addr()?;
+                let (stats, stop) = (stats.clone(), stop.clone());
+                thread::spawn(move || accept(listener, sender, stats, stop));
+                address
+            }
+            "udp" => {
+                let socket = UdpSocket::bind(address)?;
+                socket.set_read_timeout(Some(POLL_INTERVAL))?;
+                let address = socket.local_addr()?;
+                let (stats, stop) = (stats.clone(), stop.clone());
+                thread::spawn(move || receive(socket, sender, stats, stop));
+                address
+            }
+            _ => {
+                return Err(MarshalError::Config(format!(
+                    "unknown scheme in '{}' (expected tcp:// or udp://)",
+                    url
+                )))
+            }
+        };
+        eprintln!("{}: listening on {}", url, address);
+
+        Ok(NetworkSource {
+            url: url.to_string(),
+            address,
+            mapping: mapping.clone(),
+            lines,
+            parsers: HashMap::new(),
+            stats,
+            stop,
+            shutdown: shutdown_flag(),
+            idle,
+            started: Instant::now(),
+            last_line: Instant::now(),
+            records: 0,
+            errors: 0,
+            finished: false,
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.address
+    }
+
+    // Ends the stream like SIGINT/SIGTERM does, for this source only
+    pub fn shutdown(&self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+
+    // Stops the listener and prints what was received
+    fn finish(&mut self) {
+        if self.finished {
+            return;
+        }
+        self.finished = true;
+        self.stop.store(true, Ordering::Relaxed);
+
+        let elapsed = self.started.elapsed().as_secs_f64();
+        eprintln!(
+            "{}: {} peers, {} lines ({} bytes), {} records, {} bad lines in {:.2}s ({:.0} records/s)",
+            self.url,
+            self.parsers.len(),
+            self.stats.lines.load(Ordering::Relaxed),
+            self.stats.bytes.load(Ordering::Relaxed),
+            self.records,
+            self.errors,
+            elapsed,
+            self.records as f64 / elapsed.max(f64::EPSILON)
+        );
+    }
+}
+
+impl Iterator for NetworkSource {
+    type Item = Result<Record, MarshalError>;
+
+    fn next(&mut self) -> Option<Result<Record, MarshalError>> {
+        while !self.finished {
+            if self.shutdown.load(Ordering::Relaxed) || self.stop.load(Ordering::Relaxed) {
+                break;
+            }
+            match self.lines.recv_timeout(POLL_INTERVAL) {
+                Ok((peer, line)) => {
+                    self.last_line = Instant::now();
+                    let parsed = self
+                        .parsers
+                        .entry(peer)
+                        .or_insert_with(LineParser::new)
+                        .parse(&line, &self.mapping);
+                    match parsed {
+                        Some(Ok(record)) => {
+                            self.records += 1;
+                            return Some(Ok(record));
+                        }
+                        Some(Err(e)) => {
+                            self.errors += 1;
+                            return Some(Err(e));
+                        }
+                        None => {}
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    if self
+                        .idle
+                        .is_some_and(|idle| self.last_line.elapsed() >= idle)
+                    {
+                        break;
+                    }
+                }
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+        self.finish();
+        None
+    }
+}
+
+// the report is printed even when the run is aborted
+impl Drop for NetworkSource {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
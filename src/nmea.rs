@@ -0,0 +1,343 @@
+use crate::error::MarshalError;
+use crate::sources::RecordSource;
+use crate::structs::Record;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Decodes AIVDM/AIVDO sentences into records, keeping the fragments of multi-sentence
+// messages until they are complete. Only position reports (types 1, 2, 3, 18, 19 and 27)
+// produce records. The ship type of static data reports (types 5 and 24) is remembered and
+// attached to the later position reports of the same vessel, for as many vessels as the
+// capacity allows. Every other message type is ignored.
+//
+// AIS position reports only carry the second of the minute, so the time of a record is the
+// receiver time: the c: parameter of an NMEA 4 tag block (\c:1443650401*hh\!AIVDM,...), a
+// unix timestamp in front of the sentence (1443650401 !AIVDM,...) or, failing both, the
+// system clock.
+pub struct NmeaDecoder {
+    // (sequence id, channel) -> (expected fragments, payloads received so far)
+    fragments: HashMap<(String, String), (usize, Vec<String>)>,
+    // mmsi -> (ship type, message it was last seen in)
+    ship_types: HashMap<i32, (u8, u64)>,
+    capacity: usize,
+    messages: u64,
+}
+
+// Vessels whose ship type is remembered, well above what a receiver hears in a day
+pub static SHIP_TYPE_CAPACITY: usize = 100_000;
+
+impl Default for NmeaDecoder {
+    fn default() -> NmeaDecoder {
+        NmeaDecoder::new()
+    }
+}
+
+impl NmeaDecoder {
+    pub fn new() -> NmeaDecoder {
+        NmeaDecoder::with_capacity(SHIP_TYPE_CAPACITY)
+    }
+
+    // Remembers the ship type of at most `capacity` vessels, forgetting the ones heard
+    // from least recently when it is full
+    pub fn with_capacity(capacity: usize) -> NmeaDecoder {
+        NmeaDecoder {
+            fragments: HashMap::new(),
+            ship_types: HashMap::new(),
+            capacity: capacity.max(1),
+            messages: 0,
+        }
+    }
+
+    // Feeds one line, returns a record once a position report is complete
+    pub fn decode(&mut self, line: &str) -> Option<Result<Record, MarshalError>> {
+        let line = line.trim();
+        let start = line.find(['!', '$'])?;
+        let (prefix, sentence) = line.split_at(start);
+
+        if let Err(e) = verify_checksum(sentence) {
+            return Some(Err(e));
+        }
+
+        // !AIVDM,count,number,sequence,channel,payload,fill*hh
+        let body = &sentence[1..sentence.rfind('*').unwrap_or(sentence.len())];
+        let fields: Vec<&str> = body.split(',').collect();
+        if fields.len() < 7 || !(fields[0].ends_with("VDM") || fields[0].ends_with("VDO")) {
+            return None;
+        }
+
+        let count: usize = match fields[1].parse() {
+            Ok(count) => count,
+            Err(_) => return Some(Err(invalid(sentence, "bad fragment count"))),
+        };
+        let number: usize = match fields[2].parse() {
+            Ok(number) => number,
+            Err(_) => return Some(Err(invalid(sentence, "bad fragment number"))),
+        };
+
+        let payload = if count <= 1 {
+            fields[5].to_string()
+        } else {
+            let key = (fields[3].to_string(), fields[4].to_string());
+            if number == 1 {
+                self.fragments.insert(key.clone(), (count, vec![]));
+            }
+            let (expected, parts) = self.fragments.get_mut(&key)?;
+            // a missing fragment drops the whole message
+            if parts.len() + 1 != number || *expected != count {
+                self.fragments.remove(&key);
+                return None;
+            }
+            parts.push(fields[5].to_string());
+            if number < count {
+                return None;
+            }
+            self.fragments.remove(&key).unwrap().1.concat()
+        };
+
+        let t = receiver_time(prefix);
+        let bits = match unarmor(&payload) {
+            Some(bits) => bits,
+            None => return Some(Err(invalid(sentence, "bad payload character"))),
+        };
+
+        self.messages += 1;
+        if let Err(reason) = self.remember_ship_type(&bits) {
+            return Some(Err(invalid(sentence, reason)));
+        }
+
+        match decode_position(&bits, t) {
+            Ok(Some(mut record)) => {
+                match record.ship_type {
+                    Some(ship_type) => self.insert_ship_type(record.oid, ship_type),
+                    None => {
+                        if let Some((ship_type, seen)) = self.ship_types.get_mut(&record.oid) {
+                            *seen = self.messages;
+                            record.ship_type = Some(*ship_type);
+                        }
+                    }
+                }
+                Some(Ok(record))
+            }
+            Ok(None) => None,
+            Err(reason) => Some(Err(invalid(sentence, reason))),
+        }
+    }
+
+    fn remember_ship_type(&mut self, bits: &[u8]) -> Result<(), &'static str> {
+        let ship_type = match unsigned(bits, 0, 6)? {
+            5 => unsigned(bits, 232, 8)?,
+            // only part B of a type 24 carries the ship type
+            24 if unsigned(bits, 38, 2)? == 1 => unsigned(bits, 40, 8)?,
+            _ => return Ok(()),
+        };
+        if ship_type != 0 {
+            let mmsi = unsigned(bits, 8, 30)?;
+            self.insert_ship_type(mmsi as i32, ship_type as u8);
+        }
+        Ok(())
+    }
+
+    fn insert_ship_type(&mut self, mmsi: i32, ship_type: u8) {
+        if self.ship_types.len() >= self.capacity && !self.ship_types.contains_key(&mmsi) {
+            // keep the most recent half, so a full map is not scanned on every new vessel
+            let mut seen: Vec<u64> = self.ship_types.values().map(|(_, seen)| *seen).collect();
+            seen.sort_unstable();
+            let oldest_kept = seen.get(seen.len() - self.capacity / 2).copied();
+            self.ship_types
+                .retain(|_, (_, seen)| oldest_kept.is_some_and(|oldest| *seen >= oldest));
+        }
+        self.ship_types.insert(mmsi, (ship_type, self.messages));
+    }
+}
+
+fn invalid(sentence: &str, reason: &str) -> MarshalError {
+    MarshalError::InvalidRecord(format!("{} ({})", sentence, reason))
+}
+
+fn verify_checksum(sentence: &str) -> Result<(), MarshalError> {
+    let (data, checksum) = match sentence[1..].split_once('*') {
+        Some(split) => split,
+        None => return Err(invalid(sentence, "missing checksum")),
+    };
+    let expected = u8::from_str_radix(checksum.get(..2).unwrap_or(""), 16)
+        .map_err(|_| invalid(sentence, "bad checksum"))?;
+    let actual = data.bytes().fold(0u8, |acc, b| acc ^ b);
+    if actual != expected {
+        return Err(invalid(sentence, "checksum mismatch"));
+    }
+    Ok(())
+}
+
+fn receiver_time(prefix: &str) -> i32 {
+    let prefix = prefix.trim();
+
+    let from_prefix = if let Some(tags) = prefix.strip_prefix('\\') {
+        // tag block: \s:station,c:1443650401*hh\
+        tags.split(['*', '\\'])
+            .next()
+            .unwrap_or("")
+            .split(',')
+            .find_map(|tag| tag.strip_prefix("c:"))
+            .and_then(|c| c.parse::<i64>().ok())
+    } else {
+        prefix
+            .trim_end_matches([',', ';', ' ', '\t'])
+            .parse::<f64>()
+            .ok()
+            .map(|t| t as i64)
+    };
+
+    match from_prefix {
+        // some receivers write milliseconds
+        Some(t) if t > 100_000_000_000 => (t / 1000) as i32,
+        Some(t) => t as i32,
+        None => SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |now| now.as_secs() as i32),
+    }
+}
+
+// 6-bit ASCII armoring -> one bit per byte
+fn unarmor(payload: &str) -> Option<Vec<u8>> {
+    let mut bits = Vec::with_capacity(payload.len() * 6);
+    for c in payload.bytes() {
+        if !(48..=119).contains(&c) || (88..=95).contains(&c) {
+            return None;
+        }
+        let mut value = c - 48;
+        if value > 40 {
+            value -= 8;
+        }
+        for shift in (0..6).rev() {
+            bits.push((value >> shift) & 1);
+        }
+    }
+    Some(bits)
+}
+
+fn unsigned(bits: &[u8], start: usize, len: usize) -> Result<u32, &'static str> {
+    let field = bits.get(start..start + len).ok_or("payload too short")?;
+    Ok(field.iter().fold(0u32, |acc, bit| (acc << 1) | *bit as u32))
+}
+
+fn signed(bits: &[u8], start: usize, len: usize) -> Result<i32, &'static str> {
+    let value = unsigned(bits, start, len)?;
+    // sign extend from `len` bits
+    Ok(((value << (32 - len)) as i32) >> (32 - len))
+}
+
+// Offsets of the fields shared by the position reports, per message type
+struct Layout {
+    sog: (usize, usize),
+    lon: (usize, usize),
+    lat: (usize, usize),
+    cog: (usize, usize),
+    heading: Option<(usize, usize)>,
+    nav_status: Option<(usize, usize)>,
+    scale: f32,       // lon/lat units per degree
+    sog_scale: f32,   // sog units per knot
+    cog_scale: f32,   // cog units per degree
+    sog_missing: u32, // value used when sog is not available
+    cog_missing: u32, // value used when cog is not available
+}
+
+static CLASS_A: Layout = Layout {
+    sog: (50, 10),
+    lon: (61, 28),
+    lat: (89, 27),
+    cog: (116, 12),
+    heading: Some((128, 9)),
+    nav_status: Some((38, 4)),
+    scale: 600_000.0,
+    sog_scale: 10.0,
+    cog_scale: 10.0,
+    sog_missing: 1023,
+    cog_missing: 3600,
+};
+
+static CLASS_B: Layout = Layout {
+    sog: (46, 10),
+    lon: (57, 28),
+    lat: (85, 27),
+    cog: (112, 12),
+    heading: Some((124, 9)),
+    nav_status: None,
+    scale: 600_000.0,
+    sog_scale: 10.0,
+    cog_scale: 10.0,
+    sog_missing: 1023,
+    cog_missing: 3600,
+};
+
+static LONG_RANGE: Layout = Layout {
+    sog: (79, 6),
+    lon: (44, 18),
+    lat: (62, 17),
+    cog: (85, 9),
+    heading: None,
+    nav_status: Some((40, 4)),
+    scale: 600.0,
+    sog_scale: 1.0,
+    cog_scale: 1.0,
+    sog_missing: 63,
+    cog_missing: 511,
+};
+
+// Ok(None) for messages that are not position reports or have no position
+fn decode_position(bits: &[u8], t: i32) -> Result<Option<Record>, &'static str> {
+    let msg_type = unsigned(bits, 0, 6)?;
+    let layout = match msg_type {
+        1..=3 => &CLASS_A,
+        18 | 19 => &CLASS_B,
+        27 => &LONG_RANGE,
+        _ => return Ok(None),
+    };
+
+    let mmsi = unsigned(bits, 8, 30)?;
+    let lon = signed(bits, layout.lon.0, layout.lon.1)? as f32 / layout.scale;
+    let lat = signed(bits, layout.lat.0, layout.lat.1)? as f32 / layout.scale;
+
+    // 181 / 91 degrees mean "not available"
+    if lon.abs() > 180.0 || lat.abs() > 90.0 {
+        return Ok(None);
+    }
+
+    let sog = unsigned(bits, layout.sog.0, layout.sog.1)?;
+    let cog = unsigned(bits, layout.cog.0, layout.cog.1)?;
+    let heading = match layout.heading {
+        Some((start, len)) => Some(unsigned(bits, start, len)?),
+        None => None,
+    };
+    let nav_status = match layout.nav_status {
+        Some((start, len)) => Some(unsigned(bits, start, len)?),
+        None => None,
+    };
+    // only the extended class B report carries the ship type
+    let ship_type = match msg_type {
+        19 => Some(unsigned(bits, 263, 8)?),
+        _ => None,
+    };
+
+    let mut record = Record::new(mmsi as i32, t, lon, lat);
+    record.sog = (sog != layout.sog_missing).then(|| sog as f32 / layout.sog_scale);
+    record.cog = (cog < layout.cog_missing).then(|| cog as f32 / layout.cog_scale);
+    record.heading = heading.filter(|h| *h < 360).map(|h| h as f32);
+    // a nav status of 15 is "not defined", a ship type of 0 "not available"
+    record.nav_status = nav_status.filter(|s| *s != 15).map(|s| s as u8);
+    record.ship_type = ship_type.filter(|t| *t != 0).map(|t| t as u8);
+    Ok(Some(record))
+}
+
+pub fn nmea_source(path: &str) -> Result<RecordSource, MarshalError> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut decoder = NmeaDecoder::new();
+
+    Ok(Box::new(reader.lines().filter_map(
+        move |line| match line {
+            Ok(line) => decoder.decode(&line),
+            Err(e) => Some(Err(e.into())),
+        },
+    )))
+}
@@ -0,0 +1,553 @@
+use crate::config::PipelineConfig;
+use crate::dataflow::{Dataflow, Input, Stage};
+use crate::error::MarshalError;
+use crate::predictor::{self, Batch, Predictor};
+use crate::stats::{BatchStats, Latency, QueueStats, StateSample, StateSize};
+use crate::streams::StreamOperator;
+use crate::structs::{FlockIndex, Pois, Record, TrajCollection, Trajectory};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+// How late a stage finished the records pushed with a due time, in seconds
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Lag {
+    pub records: usize,
+    pub total: f64,
+    pub max: f64,
+    pub last: f64,
+}
+
+impl Lag {
+    fn add(&mut self, lag: Duration) {
+        let lag = lag.as_secs_f64();
+        self.records += 1;
+        self.total += lag;
+        self.max = self.max.max(lag);
+        self.last = lag;
+    }
+
+    pub fn mean(&self) -> f64 {
+        if self.records == 0 {
+            0.0
+        } else {
+            self.total / self.records as f64
+        }
+    }
+
+    // the last lag of the shard that fell furthest behind
+    fn merge(&mut self, other: &Lag) {
+        self.records += other.records;
+        self.total += other.total;
+        self.max = self.max.max(other.max);
+        self.last = self.last.max(other.last);
+    }
+}
+
+// Records a stage consumed and points it emitted (predicted points for predict)
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Volume {
+    pub inputs: usize,
+    pub points: usize,
+    pub kept: usize, // compress only, the points OPW keeps, see StreamOperator::compresses
+    pub evicted: usize, // idle objects dropped from the state
+}
+
+impl Volume {
+    // points per input record, e.g. above 1 for resample. compress emits every point it
+    // accepts, see compression
+    pub fn ratio(&self) -> Option<f64> {
+        (self.inputs > 0).then(|| self.points as f64 / self.inputs as f64)
+    }
+
+    // kept points per input record, for compress
+    pub fn compression(&self) -> Option<f64> {
+        (self.inputs > 0).then(|| self.kept as f64 / self.inputs as f64)
+    }
+}
+
+// Decides when the collections are scanned for idle objects: whenever event time advanced a
+// tenth of the idle timeout
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct EvictionClock {
+    event_time: Option<i32>, // newest record time seen
+    last_scan: i32,
+}
+
+impl EvictionClock {
+    // Moves event time to `t`, returns the event time to evict at when a scan is due
+    pub fn tick(&mut self, t: i32, idle_timeout: i32) -> Option<i32> {
+        let now = self.event_time.map_or(t, |newest| newest.max(t));
+        if self.event_time.is_none() {
+            self.last_scan = now;
+        }
+        self.event_time = Some(now);
+
+        if idle_timeout <= 0 || now - self.last_scan < (idle_timeout / 10).max(1) {
+            return None;
+        }
+        self.last_scan = now;
+        Some(now)
+    }
+}
+
+// What a checkpoint keeps of a pipeline: the trajectory state of every stage and the event
+// time the idle eviction is at. Borrowed when saving, owned when loaded
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PipelineState<'a> {
+    pub collections: Vec<(Stage, Cow<'a, TrajCollection>)>,
+    pub eviction: EvictionClock,
+}
+
+// Drives the records through the stages of a dataflow, one record at a time
+pub struct Pipeline {
+    pub dataflow: Dataflow,
+    pub cfg: PipelineConfig,
+    pub pois: Pois,
+    pub collections: HashMap<Stage, TrajCollection>, // state of every trajectory stage
+    pub latencies: HashMap<Stage, Latency>,          // per-record time spent in every stage
+    pub lags: HashMap<Stage, Lag>,                   // only for records pushed with push_due
+    pub volumes: HashMap<Stage, Volume>,
+    pub records: usize,
+    pub warmup: usize, // the first `warmup` records are processed but not measured
+    pub state_every: usize, // sample the state sizes every `state_every` records, 0 never
+    pub state_samples: Vec<StateSample>,
+    pub keep_evicted: bool, // hold evicted trajectories until take_evicted, otherwise drop them
+    pub evict_on_push: bool, // false when the caller drives evict_idle (e.g. for shards)
+    pub queues: Vec<QueueStats>, // between the worker threads, if the pipeline ran on several
+    pub batches: BatchStats, // when predict batches, see cfg.predict_batch_size
+    measuring_since: Option<Instant>,
+    evicted: Vec<(Stage, Trajectory)>,
+    eviction: EvictionClock,
+    operators: HashMap<Stage, Box<dyn StreamOperator>>,
+    predictor: Option<Box<dyn Predictor>>,
+    batch: Option<Batch>,
+}
+
+impl Pipeline {
+    pub fn new(
+        dataflow: Dataflow,
+        cfg: PipelineConfig,
+        pois: Pois,
+    ) -> Result<Pipeline, MarshalError> {
+        let predictor = if dataflow.contains(Stage::Predict) {
+            Some(predictor::load(&cfg)?)
+        } else {
+            None
+        };
+        let batch = (predictor.is_some() && cfg.predict_batch_size > 1).then(|| Batch::new(&cfg));
+
+        // every trajectory stage keeps its own state, predict reads the state of its input stage
+        let mut operators: HashMap<Stage, Box<dyn StreamOperator>> = HashMap::new();
+        let mut collections: HashMap<Stage, TrajCollection> = HashMap::new();
+        for spec in dataflow.stages.iter() {
+            if let Some(operator) = spec.stage.operator() {
+                operators.insert(spec.stage, operator);
+                collections.insert(spec.stage, TrajCollection::default());
+            }
+        }
+
+        Ok(Pipeline {
+            dataflow,
+            cfg,
+            pois,
+            collections,
+            latencies: HashMap::new(),
+            lags: HashMap::new(),
+            volumes: HashMap::new(),
+            records: 0,
+            warmup: 0,
+            state_every: 0,
+            state_samples: vec![],
+            keep_evicted: false,
+            measuring_since: None,
+            evicted: vec![],
+            evict_on_push: true,
+            queues: vec![],
+            batches: BatchStats::default(),
+            eviction: EvictionClock::default(),
+            operators,
+            predictor,
+            batch,
+        })
+    }
+
+    // Feeds a record through every stage, returns the points each trajectory stage emitted
+    pub fn push(&mut self, record: Record) -> Result<Vec<(Stage, Trajectory)>, MarshalError> {
+        self.process(record, vec![], None)
+    }
+
+    // Same as push for a record that was due at `due` (e.g. replayed in real time), the
+    // time between `due` and the end of every stage is added to its lag
+    pub fn push_due(
+        &mut self,
+        record: Record,
+        due: Instant,
+    ) -> Result<Vec<(Stage, Trajectory)>, MarshalError> {
+        self.process(record, vec![], Some(due))
+    }
+
+    // For a pipeline running part of a dataflow (see Dataflow::part): `upstream` holds the
+    // points the stages it reads from emitted for `record` elsewhere. Returns the points of
+    // its own stages
+    pub fn push_after(
+        &mut self,
+        record: Record,
+        upstream: Vec<(Stage, Trajectory)>,
+        due: Option<Instant>,
+    ) -> Result<Vec<(Stage, Trajectory)>, MarshalError> {
+        self.process(record, upstream, due)
+    }
+
+    fn process(
+        &mut self,
+        record: Record,
+        upstream: Vec<(Stage, Trajectory)>,
+        due: Option<Instant>,
+    ) -> Result<Vec<(Stage, Trajectory)>, MarshalError> {
+        record.validate()?;
+
+        let measured = self.records >= self.warmup;
+        if measured && self.measuring_since.is_none() {
+            self.measuring_since = Some(Instant::now());
+        }
+
+        let received = upstream.len();
+        let mut emitted: Vec<(Stage, Trajectory)> = upstream;
+
+        for spec in self.dataflow.stages.iter() {
+            // stages chained to another one consume the points it emitted for this record
+            let inputs = match spec.input {
+                Input::Raw => vec![record.clone()],
+                Input::Stage(input) => emitted
+                    .iter()
+                    .find(|(stage, _)| *stage == input)
+                    .map(|(_, points)| points.to_records())
+                    .unwrap_or_default(),
+            };
+
+            let now = Instant::now();
+
+            // (records consumed, points emitted, points kept)
+            let volume = match self.operators.get(&spec.stage) {
+                Some(operator) => {
+                    let traj_coll = self.collections.get_mut(&spec.stage).unwrap();
+                    let consumed = inputs.len();
+                    let mut outputs = Trajectory::new_empty(record.oid, usize::MAX);
+                    let mut kept = 0;
+                    for input in inputs {
+                        let first = !traj_coll.object.contains_key(&input.oid);
+                        let pushed = operator.push(input, traj_coll, &self.pois, &self.cfg);
+                        if operator.compresses() && (first || pushed.flush.is_some()) {
+                            kept += 1;
+                        }
+                        outputs.extend(pushed.points);
+                    }
+                    let produced = outputs.timestamps.len();
+                    emitted.push((spec.stage, outputs));
+                    (consumed, produced, kept)
+                }
+                None => match (&self.predictor, &mut self.batch, spec.input) {
+                    // the predictions come when the batch runs, for the objects in it
+                    (Some(predictor), Some(batch), Input::Stage(input)) => {
+                        if let Some(traj) = self.collections[&input].object.get(&record.oid) {
+                            batch.push(traj, predictor.min_points());
+                        }
+                        let predicted = match batch.due() {
+                            true => batch
+                                .run(predictor.as_ref(), measured.then_some(&mut self.batches))?,
+                            false => vec![],
+                        };
+                        let produced = predicted.iter().map(|traj| traj.timestamps.len()).sum();
+                        emitted.extend(
+                            predicted
+                                .into_iter()
+                                .filter(|traj| !traj.timestamps.is_empty())
+                                .map(|traj| (spec.stage, traj)),
+                        );
+                        (1, produced, 0)
+                    }
+                    (Some(predictor), None, Input::Stage(input)) => {
+                        let predicted = self.collections[&input].predict_for_oid(
+                            record.oid,
+                            predictor.as_ref(),
+                            &self.cfg.predict_horizons,
+                            self.cfg.predict_interval(),
+                        )?;
+                        let produced = predicted.timestamps.len();
+                        // too short a trajectory predicts nothing
+                        if produced > 0 {
+                            emitted.push((spec.stage, predicted));
+                        }
+                        (1, produced, 0)
+                    }
+                    _ => (0, 0, 0),
+                },
+            };
+
+            if measured {
+                self.latencies
+                    .entry(spec.stage)
+                    .or_default()
+                    .record(now.elapsed());
+            }
+            if let (Some(due), true) = (due, measured) {
+                self.lags
+                    .entry(spec.stage)
+                    .or_default()
+                    .add(Instant::now().saturating_duration_since(due));
+            }
+
+            let totals = self.volumes.entry(spec.stage).or_default();
+            totals.inputs += volume.0;
+            totals.points += volume.1;
+            totals.kept += volume.2;
+        }
+
+        self.records += 1;
+        if self.evict_on_push {
+            if let Some(now) = self.eviction.tick(record.t, self.cfg.idle_timeout) {
+                self.evict_idle(now);
+            }
+        }
+        if self.state_every > 0 && self.records.is_multiple_of(self.state_every) {
+            self.state_samples.push(StateSample {
+                records: self.records,
+                stages: self
+                    .state_sizes()
+                    .into_iter()
+                    .map(|(stage, size)| (stage.name(), size))
+                    .collect(),
+            });
+        }
+        Ok(emitted.split_off(received))
+    }
+
+    // Runs the trajectories still waiting for a prediction batch, at the end of the input,
+    // returns the points predicted for them
+    pub fn flush(&mut self) -> Result<Vec<(Stage, Trajectory)>, MarshalError> {
+        let (predictor, batch) = match (&self.predictor, &mut self.batch) {
+            (Some(predictor), Some(batch)) => (predictor, batch),
+            _ => return Ok(vec![]),
+        };
+        let measured = self.records >= self.warmup;
+        let predicted = batch.run(predictor.as_ref(), measured.then_some(&mut self.batches))?;
+        self.volumes.entry(Stage::Predict).or_default().points += predicted
+            .iter()
+            .map(|traj| traj.timestamps.len())
+            .sum::<usize>();
+        Ok(predicted
+            .into_iter()
+            .filter(|traj| !traj.timestamps.is_empty())
+            .map(|traj| (Stage::Predict, traj))
+            .collect())
+    }
+
+    // When the waiting prediction batch is due whether or not more records come, see poll
+    pub fn deadline(&self) -> Option<Instant> {
+        self.batch.as_ref().and_then(Batch::deadline)
+    }
+
+    // Runs the prediction batch once it waited cfg.predict_batch_wait, for the workers to
+    // call when no record came meanwhile. Returns the points predicted
+    pub fn poll(&mut self) -> Result<Vec<(Stage, Trajectory)>, MarshalError> {
+        match &self.batch {
+            Some(batch) if batch.due() => self.flush(),
+            _ => Ok(vec![]),
+        }
+    }
+
+    // Drops the objects no record arrived for in the cfg.idle_timeout seconds of event time
+    // before `now`
+    pub fn evict_idle(&mut self, now: i32) {
+        let timeout = self.cfg.idle_timeout;
+        for spec in self.dataflow.stages.iter() {
+            if let Some(collection) = self.collections.get_mut(&spec.stage) {
+                let evicted = collection.evict_idle(now.saturating_sub(timeout));
+                self.volumes.entry(spec.stage).or_default().evicted += evicted.len();
+                if self.keep_evicted {
+                    self.evicted
+                        .extend(evicted.into_iter().map(|traj| (spec.stage, traj)));
+                }
+            }
+        }
+    }
+
+    // Trajectories evicted since the last call, when keep_evicted is set
+    pub fn take_evicted(&mut self) -> Vec<(Stage, Trajectory)> {
+        std::mem::take(&mut self.evicted)
+    }
+
+    pub fn state(&self) -> PipelineState<'_> {
+        PipelineState {
+            collections: self
+                .dataflow
+                .stages
+                .iter()
+                .filter_map(|spec| {
+                    self.collections
+                        .get(&spec.stage)
+                        .map(|collection| (spec.stage, Cow::Borrowed(collection)))
+                })
+                .collect(),
+            eviction: self.eviction,
+        }
+    }
+
+    // Replaces the trajectory state with a checkpointed one, which must hold exactly the
+    // trajectory stages of this dataflow
+    pub fn restore(&mut self, state: PipelineState) -> Result<(), MarshalError> {
+        let mut collections = HashMap::new();
+        for (stage, collection) in state.collections {
+            if !self.collections.contains_key(&stage) || collections.contains_key(&stage) {
+                return Err(MarshalError::Config(format!(
+                    "the checkpoint holds state for {}, which the dataflow {} does not keep",
+                    stage, self.dataflow
+                )));
+            }
+            let mut collection = collection.into_owned();
+            collection.flock_index = self.collections[&stage].flock_index.clone();
+            for oid in collection.object.keys() {
+                collection.publish(*oid);
+            }
+            collections.insert(stage, collection);
+        }
+        if let Some(stage) = self
+            .collections
+            .keys()
+            .find(|stage| !collections.contains_key(*stage))
+        {
+            return Err(MarshalError::Config(format!(
+                "the checkpoint holds no state for {}",
+                stage
+            )));
+        }
+
+        self.collections = collections;
+        self.eviction = state.eviction;
+        Ok(())
+    }
+
+    // Current state of every trajectory stage, in dataflow order
+    pub fn state_sizes(&self) -> Vec<(Stage, StateSize)> {
+        self.dataflow
+            .stages
+            .iter()
+            .filter_map(|spec| {
+                self.collections
+                    .get(&spec.stage)
+                    .map(|collection| (spec.stage, collection.state_size()))
+            })
+            .collect()
+    }
+
+    // Gives the stages whose operator reads other objects the flock index of that stage in
+    // `indexes`, shared by every pipeline (shard) attached to the same map
+    pub fn share_flocks(&mut self, indexes: &mut HashMap<Stage, FlockIndex>) {
+        for (stage, operator) in self.operators.iter() {
+            if operator.reads_other_objects() {
+                let index = indexes.entry(*stage).or_default().clone();
+                let collection = self.collections.get_mut(stage).unwrap();
+                collection.flock_index = Some(index);
+                for oid in collection.object.keys() {
+                    collection.publish(*oid);
+                }
+            }
+        }
+    }
+
+    // Adds the state and measurements of another shard of the same dataflow. State samples
+    // are summed in the order they were taken
+    pub fn merge(&mut self, shard: Pipeline) {
+        self.records += shard.records;
+        // a shard given fewer records than its share of the warm-up measured none
+        self.warmup += shard.warmup.min(shard.records);
+        for (i, sample) in shard.state_samples.iter().enumerate() {
+            match self.state_samples.get_mut(i) {
+                Some(totals) => totals.records += sample.records,
+                None => self.state_samples.push(StateSample {
+                    records: sample.records,
+                    stages: Default::default(),
+                }),
+            }
+        }
+        self.absorb(shard);
+    }
+
+    // Adds the stages of another part of the dataflow (see Dataflow::part), which saw the
+    // same records
+    pub fn merge_part(&mut self, part: Pipeline) {
+        self.dataflow
+            .stages
+            .extend(part.dataflow.stages.iter().copied());
+        for (i, sample) in part.state_samples.iter().enumerate() {
+            if self.state_samples.len() <= i {
+                self.state_samples.push(StateSample {
+                    records: sample.records,
+                    stages: Default::default(),
+                });
+            }
+        }
+        self.absorb(part);
+    }
+
+    // The state and measurements of the stages `other` runs
+    fn absorb(&mut self, other: Pipeline) {
+        for (stage, collection) in other.collections {
+            // predict keeps a copy of the trajectories it reads, see StagedPipeline
+            if other.dataflow.contains(stage) {
+                self.collections
+                    .entry(stage)
+                    .or_default()
+                    .object
+                    .extend(collection.object);
+            }
+        }
+        for (stage, latency) in other.latencies {
+            self.latencies.entry(stage).or_default().merge(&latency);
+        }
+        for (stage, lag) in other.lags {
+            self.lags.entry(stage).or_default().merge(&lag);
+        }
+        for (stage, volume) in other.volumes {
+            let totals = self.volumes.entry(stage).or_default();
+            totals.inputs += volume.inputs;
+            totals.points += volume.points;
+            totals.kept += volume.kept;
+            totals.evicted += volume.evicted;
+        }
+
+        // the records of the samples are already counted
+        for (i, sample) in other.state_samples.into_iter().enumerate() {
+            for (stage, size) in sample.stages {
+                let total = self.state_samples[i].stages.entry(stage).or_default();
+                total.objects += size.objects;
+                total.points += size.points;
+                total.heap_bytes += size.heap_bytes;
+            }
+        }
+
+        self.measuring_since = match (self.measuring_since, other.measuring_since) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        self.batches.merge(&other.batches);
+        self.evicted.extend(other.evicted);
+        self.queues.extend(other.queues);
+    }
+
+    // Records pushed after the warm-up
+    pub fn measured(&self) -> usize {
+        self.records.saturating_sub(self.warmup)
+    }
+
+    // Measured records per second of wall-clock time since the warm-up ended, including
+    // whatever the caller does between two pushes (reading the input, writing sinks)
+    pub fn throughput(&self) -> f64 {
+        match self.measuring_since {
+            Some(since) => self.measured() as f64 / since.elapsed().as_secs_f64(),
+            None => 0.0,
+        }
+    }
+}
@@ -0,0 +1,519 @@
+use crate::config::PipelineConfig;
+use crate::error::MarshalError;
+use crate::kinematic::{Ctrv, DeadReckoning, Regression};
+use crate::stats::BatchStats;
+use crate::structs::{Coordinate, Reported, Trajectory, PREDICT_WINDOW};
+use itertools::izip;
+use proj::Proj;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+#[cfg(feature = "torch")]
+use tch::CModule;
+use tract_onnx::pb::{ModelProto, NodeProto, TensorProto};
+use tract_onnx::prelude::*;
+
+// Predicts the next position of an object from its trajectory so far, None while the
+// trajectory is too short. See rollout for the positions further ahead
+pub trait Predictor: Send {
+    fn predict(&self, traj: &Trajectory) -> Result<Option<Coordinate>, MarshalError>;
+
+    // Predictions for several trajectories, the network runs them in a single forward pass
+    fn predict_batch(
+        &self,
+        trajs: &[&Trajectory],
+    ) -> Result<Vec<Option<Coordinate>>, MarshalError> {
+        trajs.iter().map(|traj| self.predict(traj)).collect()
+    }
+
+    // The projections predict_projected takes, for the predictors that project
+    fn projection(&self) -> Result<Option<Projection>, MarshalError> {
+        Ok(None)
+    }
+
+    // predict_batch with projections the caller built, rollout builds them once for all its
+    // steps
+    fn predict_projected(
+        &self,
+        trajs: &[&Trajectory],
+        _projection: &Projection,
+    ) -> Result<Vec<Option<Coordinate>>, MarshalError> {
+        self.predict_batch(trajs)
+    }
+
+    // Points a trajectory needs before anything is predicted from it
+    fn min_points(&self) -> usize {
+        2
+    }
+}
+
+// What the predict stage runs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PredictorKind {
+    Torch,         // the TorchScript model at model_path, needs the torch feature (libtorch)
+    Onnx,          // the ONNX model at onnx_model_path, run by tract
+    DeadReckoning, // the baselines in kinematic
+    Ctrv,
+    Regression,
+}
+
+impl FromStr for PredictorKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<PredictorKind, String> {
+        match s {
+            "torch" => Ok(PredictorKind::Torch),
+            "onnx" => Ok(PredictorKind::Onnx),
+            "dead_reckoning" => Ok(PredictorKind::DeadReckoning),
+            "ctrv" => Ok(PredictorKind::Ctrv),
+            "regression" => Ok(PredictorKind::Regression),
+            _ => Err(format!(
+                "unknown predictor '{}' (expected torch, onnx, dead_reckoning, ctrv or regression)",
+                s
+            )),
+        }
+    }
+}
+
+pub fn load(cfg: &PipelineConfig) -> Result<Box<dyn Predictor>, MarshalError> {
+    match cfg.predictor {
+        #[cfg(feature = "torch")]
+        PredictorKind::Torch => Ok(Box::new(Network::Torch(CModule::load(&cfg.model_path)?))),
+        #[cfg(not(feature = "torch"))]
+        PredictorKind::Torch => Err(MarshalError::Config(
+            "built without the torch feature, use the onnx predictor".to_string(),
+        )),
+        PredictorKind::Onnx => Ok(Box::new(Network::Onnx(Box::new(load_onnx(
+            &cfg.onnx_model_path,
+        )?)))),
+        PredictorKind::DeadReckoning => Ok(Box::new(DeadReckoning)),
+        PredictorKind::Ctrv => Ok(Box::new(Ctrv)),
+        PredictorKind::Regression => Ok(Box::new(Regression)),
+    }
+}
+
+// The trained network, run by libtorch or by tract
+pub enum Network {
+    #[cfg(feature = "torch")]
+    Torch(CModule),
+    Onnx(Box<TypedRunnableModel<TypedModel>>),
+}
+
+impl Network {
+    // `n` windows of [10, 4] features to `n` normalised displacements
+    pub fn forward(&self, windows: &[f32], n: usize) -> Result<Vec<f32>, MarshalError> {
+        match self {
+            #[cfg(feature = "torch")]
+            Network::Torch(model) => Ok(Vec::<f32>::from(model.forward_ts(&[
+                tch::Tensor::of_slice(windows).reshape(&[n as i64, 10, 4]),
+                tch::Tensor::of_slice(&vec![1; n]),
+            ])?)),
+            Network::Onnx(model) => {
+                let windows = Tensor::from_shape(&[n, 10, 4], windows)?;
+                let outputs = model.run(tvec!(windows.into()))?;
+                Ok(outputs[0].as_slice::<f32>()?.to_vec())
+            }
+        }
+    }
+}
+
+impl Predictor for Network {
+    fn predict(&self, traj: &Trajectory) -> Result<Option<Coordinate>, MarshalError> {
+        let projection = Projection::new()?;
+        let (window, last) = match window(traj, &projection)? {
+            Some(window) => window,
+            None => return Ok(None),
+        };
+        let output = self.forward(&window, 1)?;
+        Ok(Some(position(last, &output, &projection)?))
+    }
+
+    fn predict_batch(
+        &self,
+        trajs: &[&Trajectory],
+    ) -> Result<Vec<Option<Coordinate>>, MarshalError> {
+        self.predict_projected(trajs, &Projection::new()?)
+    }
+
+    fn projection(&self) -> Result<Option<Projection>, MarshalError> {
+        Ok(Some(Projection::new()?))
+    }
+
+    fn predict_projected(
+        &self,
+        trajs: &[&Trajectory],
+        projection: &Projection,
+    ) -> Result<Vec<Option<Coordinate>>, MarshalError> {
+        let mut windows = vec![];
+        let mut lasts = vec![];
+        for traj in trajs {
+            match window(traj, projection)? {
+                Some((window, last)) => {
+                    windows.extend(window);
+                    lasts.push(Some(last));
+                }
+                None => lasts.push(None),
+            }
+        }
+        let n = lasts.iter().flatten().count();
+        if n == 0 {
+            return Ok(vec![None; trajs.len()]);
+        }
+
+        let outputs = self.forward(&windows, n)?;
+        let mut outputs = outputs.chunks(2);
+        lasts
+            .into_iter()
+            .map(|last| match last {
+                Some(last) => Ok(Some(position(last, outputs.next().unwrap(), projection)?)),
+                None => Ok(None),
+            })
+            .collect()
+    }
+
+    fn min_points(&self) -> usize {
+        PREDICT_WINDOW
+    }
+}
+
+// Rolls the predictions for `trajs` out `step` seconds at a time past their last points,
+// every predicted point is appended to the trajectory the next one is predicted from. The
+// rollout ends at the furthest of `horizons` and stops at each of them on the way, the step
+// that would pass one is cut short. Without a horizon past 0 only the next point is predicted,
+// as the predictors predict it: as far past the last point as the one before it. Returns the
+// predicted points of every trajectory, the ones still rolling are predicted together a step
+// at a time
+pub fn rollout(
+    predictor: &dyn Predictor,
+    mut trajs: Vec<Trajectory>,
+    horizons: &[i32],
+    step: i32,
+) -> Result<Vec<Trajectory>, MarshalError> {
+    let mut predicted: Vec<Trajectory> = trajs
+        .iter()
+        .map(|traj| Trajectory::new_empty(traj.oid, usize::MAX))
+        .collect();
+    let mut rolling: Vec<usize> = (0..trajs.len())
+        .filter(|i| trajs[*i].timestamps.len() >= 2)
+        .collect();
+    let projection = predictor.projection()?;
+    let native = horizons.iter().all(|horizon| *horizon <= 0);
+    let offsets = match native {
+        true => vec![0],
+        false => offsets(horizons, step),
+    };
+
+    let mut elapsed = 0;
+    for ahead in offsets {
+        if rolling.is_empty() {
+            break;
+        }
+        let batch: Vec<&Trajectory> = rolling.iter().map(|i| &trajs[*i]).collect();
+        let coords = match &projection {
+            Some(projection) => predictor.predict_projected(&batch, projection)?,
+            None => predictor.predict_batch(&batch)?,
+        };
+
+        let mut next = vec![];
+        for (i, coord) in rolling.into_iter().zip(coords) {
+            let coord = match coord {
+                Some(coord) => coord,
+                None => continue,
+            };
+            let traj = &mut trajs[i];
+            let n = traj.timestamps.len();
+            let (last, interval) = (
+                traj.timestamps[n - 1],
+                traj.timestamps[n - 1] - traj.timestamps[n - 2],
+            );
+            // the predictors predict a point as far past the last one as the one before it,
+            // which is moved along its course to the step
+            let t = match native {
+                true => last + interval,
+                false => last + ahead - elapsed,
+            };
+            let coord = match interval {
+                interval if interval > 0 && interval != t - last => traj.coordinates[n - 1]
+                    .extrapolate(
+                        traj.calculate_speed(&coord, &(last + interval)),
+                        traj.calculate_bearing(&coord),
+                        t - last,
+                    ),
+                _ => coord,
+            };
+            // two points at the same time have no speed
+            let (speed, bearing) = match t > last {
+                true => (
+                    traj.calculate_speed(&coord, &t),
+                    traj.calculate_bearing(&coord),
+                ),
+                false => (-1.0, -1.0),
+            };
+            let trip = *traj.trips.last().unwrap();
+            for points in [traj, &mut predicted[i]] {
+                points.insert_unbounded(
+                    coord.clone(),
+                    t,
+                    speed,
+                    bearing,
+                    -1,
+                    trip,
+                    -1,
+                    vec![],
+                    Reported::default(),
+                );
+            }
+            next.push(i);
+        }
+        rolling = next;
+        elapsed = ahead;
+    }
+    Ok(predicted)
+}
+
+// Seconds past the last point the rollout predicts points at, every `step` and every horizon
+fn offsets(horizons: &[i32], step: i32) -> Vec<i32> {
+    let end = horizons.iter().copied().max().unwrap_or(0);
+    let mut offsets: Vec<i32> = (1..)
+        .map(|k| k * step)
+        .take_while(|ahead| *ahead > 0 && *ahead < end)
+        .chain(horizons.iter().copied().filter(|horizon| *horizon > 0))
+        .collect();
+    offsets.sort_unstable();
+    offsets.dedup();
+    offsets
+}
+
+// Trajectories waiting to be predicted together, see cfg.predict_batch_size. The batch
+// runs once it is full or its first trajectory waited cfg.predict_batch_wait
+pub struct Batch {
+    size: usize,
+    wait: Duration,
+    horizons: Vec<i32>,
+    step: i32,
+    pending: Vec<(Trajectory, Instant)>, // the last points of an object when its record came
+}
+
+impl Batch {
+    pub fn new(cfg: &PipelineConfig) -> Batch {
+        Batch {
+            size: cfg.predict_batch_size,
+            wait: Duration::from_secs_f64(cfg.predict_batch_wait.max(0.0) / 1000.0),
+            horizons: cfg.predict_horizons.clone(),
+            step: cfg.predict_interval(),
+            pending: vec![],
+        }
+    }
+
+    // Trajectories too short to predict from would only take a slot
+    pub fn push(&mut self, traj: &Trajectory, min_points: usize) {
+        if traj.timestamps.len() < min_points {
+            return;
+        }
+        self.pending
+            .push((traj.tail(PREDICT_WINDOW), Instant::now()));
+    }
+
+    // When the first trajectory has waited cfg.predict_batch_wait
+    pub fn deadline(&self) -> Option<Instant> {
+        match self.pending.first() {
+            Some((_, first)) if !self.wait.is_zero() => Some(*first + self.wait),
+            _ => None,
+        }
+    }
+
+    pub fn due(&self) -> bool {
+        self.pending.len() >= self.size
+            || self
+                .deadline()
+                .is_some_and(|deadline| Instant::now() >= deadline)
+    }
+
+    // Predicts the waiting trajectories, returns the points predicted for each of them
+    pub fn run(
+        &mut self,
+        predictor: &dyn Predictor,
+        stats: Option<&mut BatchStats>,
+    ) -> Result<Vec<Trajectory>, MarshalError> {
+        if self.pending.is_empty() {
+            return Ok(vec![]);
+        }
+        let (trajs, queued): (Vec<Trajectory>, Vec<Instant>) = self.pending.drain(..).unzip();
+        let start = Instant::now();
+        let predicted = rollout(predictor, trajs, &self.horizons, self.step)?;
+        if let Some(stats) = stats {
+            stats.record(start.elapsed(), &queued);
+        }
+        Ok(predicted)
+    }
+}
+
+// Between coordinates and the web mercator metres the network works in. Building the
+// projections takes longer than a forward pass, see Predictor::projection
+pub struct Projection {
+    to_metres: Proj,
+    to_degrees: Proj,
+}
+
+impl Projection {
+    pub fn new() -> Result<Projection, MarshalError> {
+        Ok(Projection {
+            to_metres: Proj::new_known_crs("EPSG:4326", "EPSG:3857", None)?,
+            to_degrees: Proj::new_known_crs("EPSG:3857", "EPSG:4326", None)?,
+        })
+    }
+}
+
+// The network inputs for the last PREDICT_WINDOW points of a trajectory, 10 steps of two
+// time deltas and a normalised projected displacement, and the projected last point
+pub type Window = (Vec<f32>, (f32, f32));
+
+pub fn window(traj: &Trajectory, projection: &Projection) -> Result<Option<Window>, MarshalError> {
+    if traj.coordinates.len() < PREDICT_WINDOW {
+        return Ok(None);
+    }
+
+    let mut xs = vec![];
+    let mut ys = vec![];
+
+    let mut data = vec![];
+
+    for coord in traj.coordinates[traj.coordinates.len() - PREDICT_WINDOW..].iter() {
+        let projected: (f32, f32) = projection.to_metres.convert((coord.x, coord.y))?;
+        xs.push(projected.0);
+        ys.push(projected.1);
+    }
+
+    let ts = &traj.timestamps[traj.timestamps.len() - PREDICT_WINDOW..];
+
+    let tmp = ts
+        .iter()
+        .zip(ts.iter().skip(1))
+        .map(|(tsa, tsb)| (tsb - tsa) as f32 / 1800.0)
+        .collect::<Vec<_>>();
+
+    for (a, b, c, d) in izip!(
+        tmp[1..11].iter(),
+        tmp[2..12].iter(),
+        xs.iter().skip(2).zip(xs.iter().skip(3)),
+        ys.iter().skip(2).zip(ys.iter().skip(3))
+    ) {
+        data.push(a.to_owned());
+        data.push(b.to_owned());
+        data.push((c.1 - c.0 - 0.604) / 245.366);
+        data.push((d.1 - d.0 - 1.619) / 232.757);
+    }
+
+    Ok(Some((data, (*xs.last().unwrap(), *ys.last().unwrap()))))
+}
+
+// Where a normalised displacement the network predicted puts the projected `last` point
+pub fn position(
+    last: (f32, f32),
+    output: &[f32],
+    projection: &Projection,
+) -> Result<Coordinate, MarshalError> {
+    let (predlondiff, predlatdiff) = (output[0] * 245.366 + 0.604, output[1] * 232.757 + 1.619);
+
+    let predlon = last.0 + predlondiff;
+    let predlat = last.1 + predlatdiff;
+
+    Ok(Coordinate::from_tuple(
+        projection.to_degrees.convert((predlon, predlat))?,
+    ))
+}
+
+// Loads the ONNX export of the network for batches of any size
+fn load_onnx(path: &str) -> Result<TypedRunnableModel<TypedModel>, MarshalError> {
+    let onnx = tract_onnx::onnx();
+    let mut proto = onnx.proto_model_for_path(path)?;
+    unpack(&mut proto)?;
+    let batch = SymbolTable::default().sym("N");
+    Ok(onnx
+        .model_for_proto_model(&proto)?
+        .with_input_fact(
+            0,
+            f32::fact([batch.to_dim(), 10.to_dim(), 4.to_dim()]).into(),
+        )?
+        // the export recorded the batch size it was traced with
+        .with_output_fact(0, InferenceFact::default())?
+        .into_optimized()?
+        .into_runnable()?)
+}
+
+// The export packs every window as a sequence of length 1, the lengths the TorchScript
+// model is called with, and sorts the batch by length around the LSTM. tract runs neither,
+// so the LSTM is given the first step of every window and the sort, the identity when all
+// lengths are equal, is dropped along with the lengths input
+fn unpack(proto: &mut ModelProto) -> Result<(), MarshalError> {
+    let graph = proto
+        .graph
+        .as_mut()
+        .ok_or_else(|| MarshalError::Config("the ONNX model has no graph".to_string()))?;
+
+    let sorted: HashSet<String> = graph
+        .node
+        .iter()
+        .filter(|node| node.op_type == "TopK")
+        .flat_map(|node| node.output.clone())
+        .collect();
+    let step = |name: &str, value: i64| TensorProto {
+        name: name.to_string(),
+        dims: vec![1],
+        data_type: 7, // int64
+        int64_data: vec![value],
+        ..TensorProto::default()
+    };
+    graph
+        .initializer
+        .extend([step("marshal_step_0", 0), step("marshal_step_1", 1)]);
+
+    let mut nodes = vec![];
+    for mut node in graph.node.drain(..) {
+        if node.op_type == "Gather" && sorted.contains(&node.input[1]) {
+            node.op_type = "Identity".to_string();
+            node.input.truncate(1);
+            node.attribute.clear();
+        }
+        let packed = node.input.get(4).is_some_and(|lengths| !lengths.is_empty());
+        if node.op_type == "LSTM" && packed {
+            let first = format!("{}_first_step", node.name);
+            nodes.push(NodeProto {
+                name: first.clone(),
+                op_type: "Slice".to_string(),
+                input: vec![
+                    node.input[0].clone(),
+                    "marshal_step_0".to_string(), // starts
+                    "marshal_step_1".to_string(), // ends
+                    "marshal_step_0".to_string(), // axes, the sequence
+                ],
+                output: vec![first.clone()],
+                ..NodeProto::default()
+            });
+            node.input[0] = first;
+            node.input[4] = String::new();
+        }
+        nodes.push(node);
+    }
+    graph.node = nodes;
+
+    // what only fed the sort and the lengths
+    loop {
+        let used: HashSet<String> = graph
+            .node
+            .iter()
+            .flat_map(|node| node.input.clone())
+            .chain(graph.output.iter().map(|output| output.name.clone()))
+            .collect();
+        let nodes = graph.node.len();
+        graph
+            .node
+            .retain(|node| node.output.iter().any(|output| used.contains(output)));
+        if graph.node.len() == nodes {
+            graph.input.retain(|input| used.contains(&input.name));
+            return Ok(());
+        }
+    }
+}
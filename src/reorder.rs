@@ -0,0 +1,257 @@
+use crate::error::MarshalError;
+use crate::sources::RecordSource;
+use crate::structs::Record;
+use serde::{Deserialize, Serialize};
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap};
+use std::fs::File;
+use std::str::FromStr;
+
+// What happens to a record that arrives after a later point of its object was released
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LatePolicy {
+    Drop,  // silently
+    Count, // dropped, the total is reported at the end
+    Side,  // written to a side output
+}
+
+impl FromStr for LatePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<LatePolicy, String> {
+        match s {
+            "drop" => Ok(LatePolicy::Drop),
+            "count" => Ok(LatePolicy::Count),
+            "side" => Ok(LatePolicy::Side),
+            _ => Err(format!(
+                "unknown late policy '{}' (expected drop, count or side)",
+                s
+            )),
+        }
+    }
+}
+
+// A buffered record, ordered by event time and then by arrival
+#[derive(Clone, Serialize, Deserialize)]
+struct Pending {
+    t: i32,
+    seq: u64,
+    record: Record,
+}
+
+impl PartialEq for Pending {
+    fn eq(&self, other: &Pending) -> bool {
+        (self.t, self.seq) == (other.t, other.seq)
+    }
+}
+
+impl Eq for Pending {}
+
+impl PartialOrd for Pending {
+    fn partial_cmp(&self, other: &Pending) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Pending {
+    fn cmp(&self, other: &Pending) -> Ordering {
+        (self.t, self.seq).cmp(&(other.t, other.seq))
+    }
+}
+
+// Holds records back until the watermark (the highest event time seen minus the allowed
+// lateness) passes them, then releases them in event-time order. A record is too late when
+// its object already had a later point released, the operators assume t only grows per oid
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ReorderBuffer {
+    allowed_lateness: i32,
+    max_t: Option<i32>,
+    pending: BinaryHeap<Reverse<Pending>>,
+    released: HashMap<i32, i32>, // oid -> t of the last released record
+    seq: u64,
+}
+
+impl ReorderBuffer {
+    pub fn new(allowed_lateness: i32) -> ReorderBuffer {
+        ReorderBuffer {
+            allowed_lateness,
+            max_t: None,
+            pending: BinaryHeap::new(),
+            released: HashMap::new(),
+            seq: 0,
+        }
+    }
+
+    pub fn watermark(&self) -> Option<i32> {
+        self.max_t.map(|t| t.saturating_sub(self.allowed_lateness))
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    // Buffers a record, gives it back if it is too late
+    pub fn push(&mut self, record: Record) -> Result<(), Record> {
+        if let Some(last) = self.released.get(&record.oid) {
+            if record.t < *last {
+                return Err(record);
+            }
+        }
+
+        self.max_t = Some(self.max_t.map_or(record.t, |t| t.max(record.t)));
+        self.seq += 1;
+        self.pending.push(Reverse(Pending {
+            t: record.t,
+            seq: self.seq,
+            record,
+        }));
+        Ok(())
+    }
+
+    // The next record the watermark has passed
+    pub fn pop_ready(&mut self) -> Option<Record> {
+        let watermark = self.watermark()?;
+        match self.pending.peek() {
+            Some(Reverse(pending)) if pending.t <= watermark => self.pop(),
+            _ => None,
+        }
+    }
+
+    // The oldest buffered record, whatever the watermark (e.g. at the end of the input)
+    pub fn pop(&mut self) -> Option<Record> {
+        let Reverse(pending) = self.pending.pop()?;
+        self.released.insert(pending.record.oid, pending.t);
+        Some(pending.record)
+    }
+}
+
+// Puts a source in event-time order per object, see ReorderBuffer
+pub struct Reordered {
+    name: String,
+    source: RecordSource,
+    buffer: ReorderBuffer,
+    policy: LatePolicy,
+    side: Option<csv::Writer<File>>,
+    exhausted: bool,
+    consumed: usize, // items read from the source, errors included
+    late: usize,
+    max_buffered: usize,
+}
+
+impl Reordered {
+    pub fn new(
+        name: &str,
+        source: RecordSource,
+        allowed_lateness: i32,
+        policy: LatePolicy,
+        side_output: Option<&str>,
+    ) -> Result<Reordered, MarshalError> {
+        let side = match (policy, side_output) {
+            (LatePolicy::Side, Some(path)) => Some(csv::Writer::from_path(path)?),
+            (LatePolicy::Side, None) => {
+                return Err(MarshalError::Config(
+                    "on_late = \"side\" needs a side output for the late records".to_string(),
+                ))
+            }
+            _ => None,
+        };
+
+        Ok(Reordered {
+            name: name.to_string(),
+            source,
+            buffer: ReorderBuffer::new(allowed_lateness),
+            policy,
+            side,
+            exhausted: false,
+            consumed: 0,
+            late: 0,
+            max_buffered: 0,
+        })
+    }
+
+    pub fn consumed(&self) -> usize {
+        self.consumed
+    }
+
+    // Records that came too late, whatever the policy did with them
+    pub fn late(&self) -> usize {
+        self.late
+    }
+
+    // Records read but not released yet, they go into a checkpoint
+    pub fn buffer(&self) -> &ReorderBuffer {
+        &self.buffer
+    }
+
+    // Continues from a checkpoint taken after `consumed` source items, the caller has
+    // skipped them already. The allowed lateness stays the configured one
+    pub fn resume(&mut self, consumed: usize, mut buffer: ReorderBuffer) {
+        buffer.allowed_lateness = self.buffer.allowed_lateness;
+        self.buffer = buffer;
+        self.consumed = consumed;
+    }
+
+    fn too_late(&mut self, record: Record) -> Result<(), MarshalError> {
+        self.late += 1;
+        if let Some(side) = self.side.as_mut() {
+            side.serialize(&record)?;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), MarshalError> {
+        if let Some(side) = self.side.as_mut() {
+            side.flush()?;
+        }
+        if self.policy != LatePolicy::Drop && self.late > 0 {
+            eprintln!(
+                "{}: {} late records, at most {} records buffered",
+                self.name, self.late, self.max_buffered
+            );
+        }
+        Ok(())
+    }
+}
+
+impl Iterator for Reordered {
+    type Item = Result<Record, MarshalError>;
+
+    fn next(&mut self) -> Option<Result<Record, MarshalError>> {
+        loop {
+            if let Some(record) = self.buffer.pop_ready() {
+                return Some(Ok(record));
+            }
+            if self.exhausted {
+                return self.buffer.pop().map(Ok);
+            }
+
+            let item = self.source.next();
+            if item.is_some() {
+                self.consumed += 1;
+            }
+            match item {
+                Some(Ok(record)) => {
+                    if let Err(record) = self.buffer.push(record) {
+                        if let Err(e) = self.too_late(record) {
+                            return Some(Err(e));
+                        }
+                    }
+                    self.max_buffered = self.max_buffered.max(self.buffer.len());
+                }
+                // errors are not delayed
+                Some(Err(e)) => return Some(Err(e)),
+                None => {
+                    self.exhausted = true;
+                    if let Err(e) = self.finish() {
+                        return Some(Err(e));
+                    }
+                }
+            }
+        }
+    }
+}
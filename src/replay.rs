@@ -0,0 +1,65 @@
+use crate::error::MarshalError;
+use crate::sources::RecordSource;
+use crate::structs::Record;
+use std::sync::{Arc, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Maps event time to wall-clock time: the first record is due when it is read, every later
+// one (t - t0) / speedup seconds after it
+#[derive(Debug, Clone)]
+pub struct ReplayClock {
+    speedup: f64,
+    origin: Arc<OnceLock<(i32, Instant)>>,
+}
+
+impl ReplayClock {
+    pub fn new(speedup: f64) -> ReplayClock {
+        ReplayClock {
+            speedup,
+            origin: Arc::new(OnceLock::new()),
+        }
+    }
+
+    pub fn speedup(&self) -> f64 {
+        self.speedup
+    }
+
+    // When a record with event time `t` should arrive
+    pub fn due(&self, t: i32) -> Instant {
+        let (t0, start) = *self.origin.get_or_init(|| (t, Instant::now()));
+        let offset = Duration::from_secs_f64((t - t0).unsigned_abs() as f64 / self.speedup);
+        if t >= t0 {
+            start + offset
+        } else {
+            start.checked_sub(offset).unwrap_or(start)
+        }
+    }
+}
+
+// Holds every record back until it is due, records that are already late (because the
+// pipeline fell behind, or because they are out of order) pass through at once
+pub struct ReplaySource {
+    source: RecordSource,
+    clock: ReplayClock,
+}
+
+impl Iterator for ReplaySource {
+    type Item = Result<Record, MarshalError>;
+
+    fn next(&mut self) -> Option<Result<Record, MarshalError>> {
+        let record = self.source.next()?;
+        if let Ok(record) = &record {
+            let due = self.clock.due(record.t);
+            let now = Instant::now();
+            if due > now {
+                thread::sleep(due - now);
+            }
+        }
+        Some(record)
+    }
+}
+
+pub fn replay(source: RecordSource, clock: ReplayClock) -> RecordSource {
+    Box::new(ReplaySource { source, clock })
+}
@@ -0,0 +1,224 @@
+use crate::config::PipelineConfig;
+use crate::dataflow::{Input, Stage};
+use crate::pipeline::{Lag, Pipeline, Volume};
+use crate::stats::{BatchSummary, LatencySummary, QueueStats, StateSample, StateSize};
+use serde::Serialize;
+use std::fmt;
+use std::fs;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// The machine a run happened on
+#[derive(Debug, Clone, Serialize)]
+pub struct Host {
+    pub hostname: Option<String>,
+    pub os: &'static str,
+    pub arch: &'static str,
+    pub cpu_model: Option<String>,
+    pub cores: usize,
+}
+
+impl Host {
+    pub fn detect() -> Host {
+        Host {
+            hostname: fs::read_to_string("/proc/sys/kernel/hostname")
+                .ok()
+                .or_else(|| std::env::var("HOSTNAME").ok())
+                .map(|name| name.trim().to_string()),
+            os: std::env::consts::OS,
+            arch: std::env::consts::ARCH,
+            cpu_model: cpu_model(),
+            cores: std::thread::available_parallelism().map_or(1, |n| n.get()),
+        }
+    }
+}
+
+// x86 has "model name", ARM boards usually only "Model" (e.g. Raspberry Pi 4 Model B)
+fn cpu_model() -> Option<String> {
+    let cpuinfo = fs::read_to_string("/proc/cpuinfo").ok()?;
+    ["model name", "Model", "Hardware", "cpu model"]
+        .iter()
+        .find_map(|key| {
+            cpuinfo.lines().find_map(|line| {
+                let (name, value) = line.split_once(':')?;
+                (name.trim() == *key).then(|| value.trim().to_string())
+            })
+        })
+}
+
+// Peak resident set size of the process in bytes (VmHWM), None where /proc is not available
+pub fn peak_memory() -> Option<u64> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmHWM:"))?;
+    let kb: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kb * 1024)
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Dataset {
+    pub input: String,
+    pub bytes: Option<u64>, // None for live inputs
+    pub records: usize,     // records pushed, including the warm-up
+    pub warmup: usize,
+    pub skipped: usize,
+}
+
+impl Dataset {
+    pub fn new(input: &str, records: usize, warmup: usize, skipped: usize) -> Dataset {
+        Dataset {
+            input: input.to_string(),
+            bytes: fs::metadata(input).ok().map(|metadata| metadata.len()),
+            records,
+            warmup: warmup.min(records),
+            skipped,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StageReport {
+    pub stage: &'static str,
+    pub input: &'static str,
+    pub latency: Option<LatencySummary>,
+    pub volume: Volume,
+    pub compression_ratio: Option<f64>, // compress only, points OPW keeps per record consumed
+    pub lag: Option<Lag>,               // replayed runs only
+    pub state: Option<StateSize>,       // at the end of the run, None for predict
+    pub peak_state: Option<StateSize>,  // the sample with the most heap bytes
+}
+
+// Everything a run measured, written as text or as one JSON object per run
+#[derive(Debug, Clone, Serialize)]
+pub struct Report {
+    pub version: &'static str,
+    pub started: u64, // unix seconds
+    pub host: Host,
+    pub dataset: Dataset,
+    pub config: PipelineConfig,
+    pub dataflow: String,
+    pub stages: Vec<StageReport>,
+    pub queues: Vec<QueueStats>, // between the threads of --threads and --pipelined runs
+    pub batches: Option<BatchSummary>, // when predict batches its windows
+    pub wall_seconds: f64,
+    pub throughput: f64, // measured records per second
+    pub peak_memory_bytes: Option<u64>,
+    pub state_samples: Vec<StateSample>,
+}
+
+impl Report {
+    pub fn new(
+        pipeline: &Pipeline,
+        dataset: Dataset,
+        started: SystemTime,
+        throughput: f64,
+    ) -> Report {
+        let states = pipeline.state_sizes();
+        let stages = pipeline
+            .dataflow
+            .stages
+            .iter()
+            .map(|spec| {
+                let volume = pipeline
+                    .volumes
+                    .get(&spec.stage)
+                    .cloned()
+                    .unwrap_or_default();
+                StageReport {
+                    stage: spec.stage.name(),
+                    input: match spec.input {
+                        Input::Raw => "raw",
+                        Input::Stage(input) => input.name(),
+                    },
+                    latency: pipeline.latencies.get(&spec.stage).map(|l| l.summary()),
+                    compression_ratio: match spec.stage {
+                        Stage::Compress => volume.compression(),
+                        _ => None,
+                    },
+                    volume,
+                    lag: pipeline.lags.get(&spec.stage).cloned(),
+                    state: states
+                        .iter()
+                        .find(|(stage, _)| *stage == spec.stage)
+                        .map(|(_, size)| *size),
+                    peak_state: pipeline
+                        .state_samples
+                        .iter()
+                        .filter_map(|sample| sample.stages.get(spec.stage.name()))
+                        .max_by_key(|size| size.heap_bytes)
+                        .copied(),
+                }
+            })
+            .collect();
+
+        Report {
+            version: env!("CARGO_PKG_VERSION"),
+            started: started
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_secs()),
+            host: Host::detect(),
+            dataset,
+            config: pipeline.cfg.clone(),
+            dataflow: pipeline.dataflow.to_string(),
+            stages,
+            queues: pipeline.queues.clone(),
+            batches: (pipeline.batches.batches > 0).then(|| pipeline.batches.summary()),
+            wall_seconds: started
+                .elapsed()
+                .map_or(0.0, |elapsed| elapsed.as_secs_f64()),
+            throughput,
+            peak_memory_bytes: peak_memory(),
+            state_samples: pipeline.state_samples.clone(),
+        }
+    }
+
+    fn stage(&self, stage: Stage) -> Option<&StageReport> {
+        self.stages
+            .iter()
+            .find(|report| report.stage == stage.name())
+    }
+}
+
+// input -> mean nanoseconds per record of clean,resample,compress,predict, followed by the
+// latency distribution and state size of every stage, the prediction batches, the queues
+// between threads and the throughput
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mean = |stage: Stage| {
+            self.stage(stage)
+                .and_then(|report| report.latency.as_ref())
+                .map_or(0.0, |latency| latency.mean_ns)
+        };
+
+        write!(
+            f,
+            "{} -> {:.3},{:.3},{:.3},{:.3}",
+            self.dataset.input,
+            mean(Stage::Clean),
+            mean(Stage::Resample),
+            mean(Stage::Compress),
+            mean(Stage::Predict)
+        )?;
+        for report in self.stages.iter() {
+            if let Some(latency) = &report.latency {
+                write!(f, "\n  {}: {}", report.stage, latency)?;
+            }
+            if let Some(state) = &report.state {
+                write!(f, "\n  {} state: {}", report.stage, state)?;
+            }
+        }
+        if let Some(batches) = &self.batches {
+            write!(f, "\n  predict batches: {}", batches)?;
+            write!(f, "\n  predict forward: {}", batches.forward)?;
+            write!(f, "\n  predict waited: {}", batches.waited)?;
+        }
+        for queue in self.queues.iter() {
+            write!(f, "\n  queue {}", queue)?;
+        }
+        write!(
+            f,
+            "\n  {} records ({} warm-up), {:.0} records/s",
+            self.dataset.records - self.dataset.warmup,
+            self.dataset.warmup,
+            self.throughput
+        )
+    }
+}
@@ -0,0 +1,159 @@
+use crate::pipeline::{EvictionClock, Pipeline};
+use crate::stats::QueueStats;
+use crate::structs::Record;
+use crate::workers::{queue, Inbox, Link, WorkerOutput, Workers};
+use std::collections::HashMap;
+use std::sync::mpsc::{self, Iter, Receiver, RecvTimeoutError, Sender, TryIter};
+use std::thread::{self, JoinHandle};
+use std::time::Instant;
+
+enum Message {
+    Record(Record, Option<Instant>),
+    Evict(i32), // event time to evict idle objects at
+}
+
+// Runs a pipeline per worker thread and sends every record to the shard of its oid, so the
+// records of an object keep their order and its state lives in a single shard. Idle objects
+// are evicted on a single event-time clock, at the same records as a single pipeline would.
+// Stages that look at other objects (flocks) share an index of the last point of every
+// object, which makes their output depend on how far the other shards are
+pub struct ShardedPipeline {
+    inputs: Vec<Link<Message>>,
+    outputs: Receiver<WorkerOutput>,
+    workers: Vec<JoinHandle<Pipeline>>,
+    queues: Vec<QueueStats>, // of the inputs, once they are closed
+    eviction: EvictionClock,
+    idle_timeout: i32,
+}
+
+impl ShardedPipeline {
+    // `shards` are pipelines of the same dataflow, each fed through a queue of `queue_size`
+    // records
+    pub fn new(mut shards: Vec<Pipeline>, queue_size: usize) -> ShardedPipeline {
+        let idle_timeout = shards[0].cfg.idle_timeout;
+        let mut indexes = HashMap::new();
+        for shard in shards.iter_mut() {
+            shard.share_flocks(&mut indexes);
+            shard.evict_on_push = false;
+        }
+
+        let (sender, outputs) = mpsc::channel();
+        let mut inputs = vec![];
+        let mut workers = vec![];
+        for (i, shard) in shards.into_iter().enumerate() {
+            let name = format!("shard-{}", i);
+            let (input, messages) = queue("input", &name, queue_size);
+            let sender = sender.clone();
+            inputs.push(input);
+            workers.push(
+                thread::Builder::new()
+                    .name(name)
+                    .spawn(move || work(shard, messages, sender))
+                    .unwrap(),
+            );
+        }
+
+        ShardedPipeline {
+            inputs,
+            outputs,
+            workers,
+            queues: vec![],
+            eviction: EvictionClock::default(),
+            idle_timeout,
+        }
+    }
+
+    pub fn shards(&self) -> usize {
+        self.workers.len()
+    }
+}
+
+impl Workers for ShardedPipeline {
+    // Queues a record on the shard of its oid
+    fn push(&mut self, record: Record, due: Option<Instant>) {
+        let shard = record.oid.rem_euclid(self.inputs.len() as i32) as usize;
+        let evict = self.eviction.tick(record.t, self.idle_timeout);
+        // the worker only hangs up when it panicked, join passes that on
+        self.inputs[shard].send(Message::Record(record, due));
+        if let Some(now) = evict {
+            for input in self.inputs.iter_mut() {
+                input.send(Message::Evict(now));
+            }
+        }
+    }
+
+    fn ready(&self) -> TryIter<'_, WorkerOutput> {
+        self.outputs.try_iter()
+    }
+
+    fn close(&mut self) -> Iter<'_, WorkerOutput> {
+        self.queues
+            .extend(self.inputs.drain(..).map(|input| input.stats));
+        self.outputs.iter()
+    }
+
+    fn join(mut self: Box<Self>) -> Pipeline {
+        self.queues
+            .extend(self.inputs.drain(..).map(|input| input.stats));
+        let mut shards = self.workers.into_iter().map(|worker| match worker.join() {
+            Ok(pipeline) => pipeline,
+            Err(panic) => std::panic::resume_unwind(panic),
+        });
+        let mut pipeline = shards.next().unwrap();
+        for shard in shards {
+            pipeline.merge(shard);
+        }
+        pipeline.queues = self.queues;
+        pipeline
+    }
+}
+
+fn work(
+    mut pipeline: Pipeline,
+    mut messages: Inbox<Message>,
+    outputs: Sender<WorkerOutput>,
+) -> Pipeline {
+    loop {
+        let output = match messages.recv_until(pipeline.deadline()) {
+            Ok(Message::Record(record, due)) => {
+                let emitted = match due {
+                    Some(due) => pipeline.push_due(record, due),
+                    None => pipeline.push(record),
+                };
+                // a record only goes to one shard
+                emitted
+                    .map(|emitted| (emitted, pipeline.take_evicted()))
+                    .map_err(|e| (None, e))
+            }
+            Ok(Message::Evict(now)) => {
+                pipeline.evict_idle(now);
+                match pipeline.take_evicted() {
+                    evicted if evicted.is_empty() => continue,
+                    evicted => Ok((vec![], evicted)),
+                }
+            }
+            // the prediction batch waited long enough for records of this shard
+            Err(RecvTimeoutError::Timeout) => match pipeline.poll() {
+                Ok(emitted) if emitted.is_empty() => continue,
+                polled => polled
+                    .map(|emitted| (emitted, vec![]))
+                    .map_err(|e| (None, e)),
+            },
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        if outputs.send(output).is_err() {
+            return pipeline;
+        }
+    }
+    match pipeline.flush() {
+        Ok(emitted) if emitted.is_empty() => {}
+        flushed => {
+            let _ = outputs.send(
+                flushed
+                    .map(|emitted| (emitted, vec![]))
+                    .map_err(|e| (None, e)),
+            );
+        }
+    }
+    pipeline
+}
@@ -0,0 +1,480 @@
+use crate::columnar::{ArrowIpcSink, ParquetSink, BATCH_SIZE};
+use crate::dataflow::Stage;
+use crate::error::MarshalError;
+use crate::structs::Trajectory;
+use serde::Serialize;
+use serde_json::json;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+// Receives the points every stage emits, one record at a time
+pub trait Sink {
+    fn write(&mut self, stage: Stage, points: &Trajectory) -> Result<(), MarshalError>;
+
+    // Flushes buffered output, nothing may be written afterwards
+    fn finish(&mut self) -> Result<(), MarshalError>;
+
+    // Flushes what was written so far for a checkpoint and returns the length of the file,
+    // which a resumed run cuts it back to and appends to. None for the formats that cannot
+    // be appended to, see appendable
+    fn checkpoint(&mut self) -> Result<Option<u64>, MarshalError> {
+        Ok(None)
+    }
+}
+
+// One emitted point, as it is written by the row based sinks
+#[derive(Serialize)]
+pub struct Point<'a> {
+    pub stage: &'static str,
+    pub oid: i32,
+    pub lon: f32,
+    pub lat: f32,
+    pub speed: f32,
+    pub bearing: f32,
+    pub stoped: i8,
+    pub trip: i32,
+    pub timestamp: i32,
+    pub poi_id: i32,
+    pub gps: &'a [i32],
+    // as reported by the input, None when it does not carry them
+    pub sog: Option<f32>,
+    pub cog: Option<f32>,
+    pub heading: Option<f32>,
+    pub nav_status: Option<u8>,
+    pub ship_type: Option<u8>,
+}
+
+pub fn points(stage: Stage, traj: &Trajectory) -> impl Iterator<Item = Point<'_>> {
+    (0..traj.timestamps.len()).map(move |i| Point {
+        stage: stage.name(),
+        oid: traj.oid,
+        lon: traj.coordinates[i].x,
+        lat: traj.coordinates[i].y,
+        speed: traj.speed[i],
+        bearing: traj.bearing[i],
+        stoped: traj.stoped[i],
+        trip: traj.trips[i],
+        timestamp: traj.timestamps[i],
+        poi_id: traj.pois[i],
+        gps: &traj.gps[i],
+        sog: traj.reported[i].sog,
+        cog: traj.reported[i].cog,
+        heading: traj.reported[i].heading,
+        nav_status: traj.reported[i].nav_status,
+        ship_type: traj.reported[i].ship_type,
+    })
+}
+
+pub struct CsvSink {
+    writer: csv::Writer<BufWriter<File>>,
+}
+
+impl CsvSink {
+    pub fn create(path: &str) -> Result<CsvSink, MarshalError> {
+        let mut writer = csv::Writer::from_writer(BufWriter::new(File::create(path)?));
+        write_header(&mut writer)?;
+        Ok(CsvSink { writer })
+    }
+
+    // Continues the file a checkpointed run wrote `length` bytes of, header included
+    pub fn append(path: &str, length: u64) -> Result<CsvSink, MarshalError> {
+        Ok(CsvSink {
+            writer: csv::Writer::from_writer(BufWriter::new(reopen(path, length)?)),
+        })
+    }
+}
+
+fn write_header(writer: &mut csv::Writer<BufWriter<File>>) -> Result<(), MarshalError> {
+    writer.write_record([
+        "stage",
+        "oid",
+        "lon",
+        "lat",
+        "speed",
+        "bearing",
+        "stoped",
+        "trip",
+        "timestamp",
+        "poi_id",
+        "gps",
+        "sog",
+        "cog",
+        "heading",
+        "nav_status",
+        "ship_type",
+    ])?;
+    Ok(())
+}
+
+impl Sink for CsvSink {
+    fn write(&mut self, stage: Stage, traj: &Trajectory) -> Result<(), MarshalError> {
+        for point in points(stage, traj) {
+            // flocked oids are joined with ';' to keep them in a single column
+            let gps: Vec<String> = point.gps.iter().map(|oid| oid.to_string()).collect();
+            self.writer.write_record(&[
+                point.stage.to_string(),
+                point.oid.to_string(),
+                point.lon.to_string(),
+                point.lat.to_string(),
+                point.speed.to_string(),
+                point.bearing.to_string(),
+                point.stoped.to_string(),
+                point.trip.to_string(),
+                point.timestamp.to_string(),
+                point.poi_id.to_string(),
+                gps.join(";"),
+                optional(point.sog),
+                optional(point.cog),
+                optional(point.heading),
+                optional(point.nav_status),
+                optional(point.ship_type),
+            ])?;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), MarshalError> {
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    fn checkpoint(&mut self) -> Result<Option<u64>, MarshalError> {
+        self.writer.flush()?;
+        Ok(Some(self.writer.get_ref().get_ref().metadata()?.len()))
+    }
+}
+
+// Opens `path` cut back to `length` bytes, for a resumed run to write after them. What the
+// interrupted run wrote after its last checkpoint is written again
+fn reopen(path: &str, length: u64) -> Result<File, MarshalError> {
+    let mut file = OpenOptions::new().write(true).open(path)?;
+    file.set_len(length)?;
+    file.seek(SeekFrom::End(0))?;
+    Ok(file)
+}
+
+// Missing values are left empty
+fn optional<T: ToString>(value: Option<T>) -> String {
+    value.map_or_else(String::new, |v| v.to_string())
+}
+
+pub struct JsonLinesSink {
+    writer: BufWriter<File>,
+}
+
+impl JsonLinesSink {
+    pub fn create(path: &str) -> Result<JsonLinesSink, MarshalError> {
+        Ok(JsonLinesSink {
+            writer: BufWriter::new(File::create(path)?),
+        })
+    }
+
+    // Continues the file a checkpointed run wrote `length` bytes of
+    pub fn append(path: &str, length: u64) -> Result<JsonLinesSink, MarshalError> {
+        Ok(JsonLinesSink {
+            writer: BufWriter::new(reopen(path, length)?),
+        })
+    }
+}
+
+impl Sink for JsonLinesSink {
+    fn write(&mut self, stage: Stage, traj: &Trajectory) -> Result<(), MarshalError> {
+        for point in points(stage, traj) {
+            serde_json::to_writer(&mut self.writer, &point)
+                .map_err(|e| MarshalError::Io(e.into()))?;
+            self.writer.write_all(b"\n")?;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), MarshalError> {
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    fn checkpoint(&mut self) -> Result<Option<u64>, MarshalError> {
+        self.writer.flush()?;
+        Ok(Some(self.writer.get_ref().metadata()?.len()))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Geometry {
+    Points, // a Point feature per emitted point
+    Trips,  // a LineString feature per trip of every object
+}
+
+impl FromStr for Geometry {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Geometry, String> {
+        match s {
+            "points" => Ok(Geometry::Points),
+            "trips" => Ok(Geometry::Trips),
+            _ => Err(format!(
+                "unknown geometry '{}' (expected points or trips)",
+                s
+            )),
+        }
+    }
+}
+
+// A trip being collected: its id, coordinates and timestamps
+type OpenTrip = (i32, Vec<[f32; 2]>, Vec<i32>);
+
+// A FeatureCollection written feature by feature, the collection is closed by finish()
+pub struct GeoJsonSink {
+    writer: BufWriter<File>,
+    geometry: Geometry,
+    features: usize,
+    // points of the trip every (stage, oid) is currently on, written once the trip ends
+    trips: HashMap<(Stage, i32), OpenTrip>,
+}
+
+impl GeoJsonSink {
+    pub fn create(path: &str, geometry: Geometry) -> Result<GeoJsonSink, MarshalError> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(b"{\"type\":\"FeatureCollection\",\"features\":[\n")?;
+        Ok(GeoJsonSink {
+            writer,
+            geometry,
+            features: 0,
+            trips: HashMap::new(),
+        })
+    }
+
+    fn write_feature(&mut self, feature: serde_json::Value) -> Result<(), MarshalError> {
+        if self.features > 0 {
+            self.writer.write_all(b",\n")?;
+        }
+        serde_json::to_writer(&mut self.writer, &feature)
+            .map_err(|e| MarshalError::Io(e.into()))?;
+        self.features += 1;
+        Ok(())
+    }
+
+    fn write_trip(
+        &mut self,
+        stage: Stage,
+        oid: i32,
+        trip: i32,
+        coordinates: Vec<[f32; 2]>,
+        timestamps: Vec<i32>,
+    ) -> Result<(), MarshalError> {
+        // a single fix is not a valid LineString
+        let geometry = if coordinates.len() == 1 {
+            json!({"type": "Point", "coordinates": coordinates[0]})
+        } else {
+            json!({"type": "LineString", "coordinates": coordinates})
+        };
+        self.write_feature(json!({
+            "type": "Feature",
+            "geometry": geometry,
+            "properties": {
+                "stage": stage.name(),
+                "oid": oid,
+                "trip": trip,
+                "start": timestamps.first(),
+                "end": timestamps.last(),
+                "points": timestamps.len(),
+            }
+        }))
+    }
+}
+
+impl Sink for GeoJsonSink {
+    fn write(&mut self, stage: Stage, traj: &Trajectory) -> Result<(), MarshalError> {
+        for point in points(stage, traj) {
+            match self.geometry {
+                Geometry::Points => self.write_feature(json!({
+                    "type": "Feature",
+                    "geometry": {"type": "Point", "coordinates": [point.lon, point.lat]},
+                    "properties": {
+                        "stage": point.stage,
+                        "oid": point.oid,
+                        "speed": point.speed,
+                        "bearing": point.bearing,
+                        "stoped": point.stoped,
+                        "trip": point.trip,
+                        "timestamp": point.timestamp,
+                        "poi_id": point.poi_id,
+                        "gps": point.gps,
+                        "sog": point.sog,
+                        "cog": point.cog,
+                        "heading": point.heading,
+                        "nav_status": point.nav_status,
+                        "ship_type": point.ship_type,
+                    }
+                }))?,
+                Geometry::Trips => {
+                    let key = (stage, point.oid);
+                    let ended = match self.trips.get(&key) {
+                        Some((trip, _, _)) => *trip != point.trip,
+                        None => false,
+                    };
+                    if ended {
+                        let (trip, coordinates, timestamps) = self.trips.remove(&key).unwrap();
+                        self.write_trip(stage, point.oid, trip, coordinates, timestamps)?;
+                    }
+                    let (_, coordinates, timestamps) = self
+                        .trips
+                        .entry(key)
+                        .or_insert_with(|| (point.trip, vec![], vec![]));
+                    coordinates.push([point.lon, point.lat]);
+                    timestamps.push(point.timestamp);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), MarshalError> {
+        // trips still open at the end of the stream
+        let mut open: Vec<_> = self.trips.drain().collect();
+        open.sort_by_key(|((stage, oid), _)| (stage.name(), *oid));
+        for ((stage, oid), (trip, coordinates, timestamps)) in open {
+            self.write_trip(stage, oid, trip, coordinates, timestamps)?;
+        }
+        self.writer.write_all(b"\n]}\n")?;
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+// Opens a sink, the format follows the extension: .csv, .jsonl/.ndjson, .geojson,
+// .parquet or .arrow (Arrow IPC file)
+pub fn open_sink(path: &str, geometry: Geometry) -> Result<Box<dyn Sink>, MarshalError> {
+    match extension(path).as_str() {
+        "csv" => Ok(Box::new(CsvSink::create(path)?)),
+        "jsonl" | "ndjson" => Ok(Box::new(JsonLinesSink::create(path)?)),
+        "geojson" => Ok(Box::new(GeoJsonSink::create(path, geometry)?)),
+        "parquet" => Ok(Box::new(ParquetSink::create(path, BATCH_SIZE)?)),
+        "arrow" | "ipc" => Ok(Box::new(ArrowIpcSink::create(path, BATCH_SIZE)?)),
+        _ => Err(MarshalError::Config(format!(
+            "cannot tell the sink format of '{}' (expected .csv, .jsonl, .geojson, .parquet or .arrow)",
+            path
+        ))),
+    }
+}
+
+// Opens a sink of a checkpointed run to continue it, see Sink::checkpoint. `lengths` are the
+// lengths of the files the checkpoint was taken with
+pub fn resume_sink(path: &str, lengths: &[(String, u64)]) -> Result<Box<dyn Sink>, MarshalError> {
+    let length = match lengths.iter().find(|(written, _)| written == path) {
+        Some((_, length)) => *length,
+        None => {
+            return Err(MarshalError::Config(format!(
+                "'{}' is not a sink of the checkpointed run, resume with the same sinks",
+                path
+            )))
+        }
+    };
+    match extension(path).as_str() {
+        "csv" => Ok(Box::new(CsvSink::append(path, length)?)),
+        "jsonl" | "ndjson" => Ok(Box::new(JsonLinesSink::append(path, length)?)),
+        _ => Err(not_appendable(path)),
+    }
+}
+
+// Whether a resumed run can continue the sink, only the line based formats can be appended to
+pub fn appendable(path: &str) -> Result<(), MarshalError> {
+    match extension(path).as_str() {
+        "csv" | "jsonl" | "ndjson" => Ok(()),
+        _ => Err(not_appendable(path)),
+    }
+}
+
+fn not_appendable(path: &str) -> MarshalError {
+    MarshalError::Config(format!(
+        "a resumed run cannot append to '{}', checkpointed runs write .csv or .jsonl sinks",
+        path
+    ))
+}
+
+fn extension(path: &str) -> String {
+    Path::new(path)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .unwrap_or("")
+        .to_ascii_lowercase()
+}
+
+// "clean=out/clean.csv" sends the points emitted by clean to out/clean.csv
+#[derive(Debug, Clone)]
+pub struct SinkSpec {
+    pub stage: Stage,
+    pub path: String,
+}
+
+impl FromStr for SinkSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<SinkSpec, String> {
+        match s.split_once('=') {
+            Some((stage, path)) => Ok(SinkSpec {
+                stage: stage.trim().parse()?,
+                path: path.trim().to_string(),
+            }),
+            None => Err(format!("expected <stage>=<path>, got '{}'", s)),
+        }
+    }
+}
+
+// The sinks of every stage, fed with what Pipeline::push returns
+pub struct Sinks {
+    sinks: Vec<(SinkSpec, Box<dyn Sink>)>,
+}
+
+impl Sinks {
+    pub fn open(specs: &[SinkSpec], geometry: Geometry) -> Result<Sinks, MarshalError> {
+        let mut sinks = vec![];
+        for spec in specs {
+            sinks.push((spec.clone(), open_sink(&spec.path, geometry)?));
+        }
+        Ok(Sinks { sinks })
+    }
+
+    pub fn resume(specs: &[SinkSpec], lengths: &[(String, u64)]) -> Result<Sinks, MarshalError> {
+        let mut sinks = vec![];
+        for spec in specs {
+            sinks.push((spec.clone(), resume_sink(&spec.path, lengths)?));
+        }
+        Ok(Sinks { sinks })
+    }
+
+    // The path and length of every sink, see Sink::checkpoint
+    pub fn checkpoint(&mut self) -> Result<Vec<(String, u64)>, MarshalError> {
+        let mut lengths = vec![];
+        for (spec, sink) in self.sinks.iter_mut() {
+            match sink.checkpoint()? {
+                Some(length) => lengths.push((spec.path.clone(), length)),
+                None => return Err(not_appendable(&spec.path)),
+            }
+        }
+        Ok(lengths)
+    }
+
+    pub fn write(&mut self, emitted: &[(Stage, Trajectory)]) -> Result<(), MarshalError> {
+        for (stage, points) in emitted {
+            if points.timestamps.is_empty() {
+                continue;
+            }
+            for (spec, sink) in self.sinks.iter_mut() {
+                if spec.stage == *stage {
+                    sink.write(*stage, points)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn finish(&mut self) -> Result<(), MarshalError> {
+        for (_, sink) in self.sinks.iter_mut() {
+            sink.finish()?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,324 @@
+use crate::error::MarshalError;
+use crate::network::{is_network, network_source};
+use crate::nmea::nmea_source;
+use crate::structs::Record;
+use arrow::array::{
+    Array, ArrayRef, ArrowPrimitiveType, Float32Array, Int32Array, Int64Array, PrimitiveArray,
+    UInt8Array,
+};
+use arrow::compute::cast;
+use arrow::datatypes::{DataType, TimeUnit};
+use arrow::error::ArrowError;
+use arrow::ipc::reader::FileReader;
+use arrow::record_batch::RecordBatch;
+use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+use std::fs::File;
+use std::path::Path;
+use std::str::FromStr;
+
+// Every input format ends up as a stream of records
+pub type RecordSource = Box<dyn Iterator<Item = Result<Record, MarshalError>>>;
+
+// Which input column holds each Record field, e.g. "oid=mmsi,t=ts,lon=longitude,lat=latitude".
+// The columns of sog, cog, heading, nav_status and ship_type may be missing from the input
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ColumnMapping {
+    pub oid: String,
+    pub t: String,
+    pub lon: String,
+    pub lat: String,
+    pub sog: String,
+    pub cog: String,
+    pub heading: String,
+    pub nav_status: String,
+    pub ship_type: String,
+}
+
+impl Default for ColumnMapping {
+    fn default() -> ColumnMapping {
+        ColumnMapping {
+            oid: "oid".to_string(),
+            t: "t".to_string(),
+            lon: "lon".to_string(),
+            lat: "lat".to_string(),
+            sog: "sog".to_string(),
+            cog: "cog".to_string(),
+            heading: "heading".to_string(),
+            nav_status: "nav_status".to_string(),
+            ship_type: "ship_type".to_string(),
+        }
+    }
+}
+
+impl ColumnMapping {
+    // (field, column) pairs
+    fn pairs(&self) -> [(&'static str, &str); 9] {
+        [
+            ("oid", &self.oid),
+            ("t", &self.t),
+            ("lon", &self.lon),
+            ("lat", &self.lat),
+            ("sog", &self.sog),
+            ("cog", &self.cog),
+            ("heading", &self.heading),
+            ("nav_status", &self.nav_status),
+            ("ship_type", &self.ship_type),
+        ]
+    }
+
+    // Renames the mapped columns of a header to the Record field names
+    pub(crate) fn rename(&self, headers: &csv::StringRecord) -> csv::StringRecord {
+        headers
+            .iter()
+            .map(|header| {
+                self.pairs()
+                    .iter()
+                    .find(|(_, column)| *column == header)
+                    .map_or(header, |(field, _)| *field)
+            })
+            .collect()
+    }
+}
+
+impl FromStr for ColumnMapping {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ColumnMapping, String> {
+        let mut mapping = ColumnMapping::default();
+        for pair in s.split(',').filter(|pair| !pair.trim().is_empty()) {
+            let (field, column) = pair
+                .split_once('=')
+                .ok_or_else(|| format!("expected <field>=<column>, got '{}'", pair))?;
+            let column = column.trim().to_string();
+            match field.trim() {
+                "oid" => mapping.oid = column,
+                "t" => mapping.t = column,
+                "lon" => mapping.lon = column,
+                "lat" => mapping.lat = column,
+                "sog" => mapping.sog = column,
+                "cog" => mapping.cog = column,
+                "heading" => mapping.heading = column,
+                "nav_status" => mapping.nav_status = column,
+                "ship_type" => mapping.ship_type = column,
+                other => return Err(format!("unknown record field '{}'", other)),
+            }
+        }
+        Ok(mapping)
+    }
+}
+
+// Opens an input file, the format follows the extension: .parquet, .arrow/.ipc/.feather,
+// .nmea/.ais (raw AIVDM sentences) or csv. tcp:// and udp:// inputs listen for live records
+pub fn open_source(path: &str, mapping: &ColumnMapping) -> Result<RecordSource, MarshalError> {
+    if is_network(path) {
+        return network_source(path, mapping);
+    }
+
+    let extension = Path::new(path)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .unwrap_or("")
+        .to_ascii_lowercase();
+
+    match extension.as_str() {
+        "parquet" => parquet_source(path, mapping),
+        "arrow" | "ipc" | "feather" => ipc_source(path, mapping),
+        "nmea" | "ais" => nmea_source(path),
+        _ => csv_source(path, mapping),
+    }
+}
+
+pub fn csv_source(path: &str, mapping: &ColumnMapping) -> Result<RecordSource, MarshalError> {
+    let mut reader = csv::Reader::from_path(path)?;
+
+    // rename the mapped columns to the Record field names and let serde do the rest
+    let headers = mapping.rename(reader.headers()?);
+    reader.set_headers(headers);
+
+    Ok(Box::new(
+        reader
+            .into_deserialize::<Record>()
+            .map(|record| record.map_err(MarshalError::from)),
+    ))
+}
+
+pub fn parquet_source(path: &str, mapping: &ColumnMapping) -> Result<RecordSource, MarshalError> {
+    let reader = ParquetRecordBatchReaderBuilder::try_new(File::open(path)?)?.build()?;
+    Ok(Box::new(BatchRecords::new(
+        Box::new(reader),
+        mapping.clone(),
+    )))
+}
+
+pub fn ipc_source(path: &str, mapping: &ColumnMapping) -> Result<RecordSource, MarshalError> {
+    let reader = FileReader::try_new(File::open(path)?, None)?;
+    Ok(Box::new(BatchRecords::new(
+        Box::new(reader),
+        mapping.clone(),
+    )))
+}
+
+// Columns of a single batch, already converted to the Record types
+struct Columns {
+    oid: Int32Array,
+    t: Int32Array,
+    lon: Float32Array,
+    lat: Float32Array,
+    sog: Option<Float32Array>,
+    cog: Option<Float32Array>,
+    heading: Option<Float32Array>,
+    nav_status: Option<UInt8Array>,
+    ship_type: Option<UInt8Array>,
+}
+
+// Flattens a stream of RecordBatches into records
+pub struct BatchRecords {
+    batches: Box<dyn Iterator<Item = Result<RecordBatch, ArrowError>>>,
+    mapping: ColumnMapping,
+    current: Option<Columns>,
+    row: usize,
+}
+
+impl BatchRecords {
+    pub fn new(
+        batches: Box<dyn Iterator<Item = Result<RecordBatch, ArrowError>>>,
+        mapping: ColumnMapping,
+    ) -> BatchRecords {
+        BatchRecords {
+            batches,
+            mapping,
+            current: None,
+            row: 0,
+        }
+    }
+
+    fn columns(&self, batch: &RecordBatch) -> Result<Columns, MarshalError> {
+        Ok(Columns {
+            oid: to_i32(column(batch, &self.mapping.oid)?)?,
+            t: to_seconds(column(batch, &self.mapping.t)?)?,
+            lon: to_f32(column(batch, &self.mapping.lon)?)?,
+            lat: to_f32(column(batch, &self.mapping.lat)?)?,
+            sog: optional_column(batch, &self.mapping.sog, to_f32)?,
+            cog: optional_column(batch, &self.mapping.cog, to_f32)?,
+            heading: optional_column(batch, &self.mapping.heading, to_f32)?,
+            nav_status: optional_column(batch, &self.mapping.nav_status, to_u8)?,
+            ship_type: optional_column(batch, &self.mapping.ship_type, to_u8)?,
+        })
+    }
+}
+
+impl Iterator for BatchRecords {
+    type Item = Result<Record, MarshalError>;
+
+    fn next(&mut self) -> Option<Result<Record, MarshalError>> {
+        loop {
+            if let Some(columns) = &self.current {
+                if self.row < columns.oid.len() {
+                    let i = self.row;
+                    self.row += 1;
+
+                    if columns.oid.is_null(i)
+                        || columns.t.is_null(i)
+                        || columns.lon.is_null(i)
+                        || columns.lat.is_null(i)
+                    {
+                        return Some(Err(MarshalError::InvalidRecord(format!(
+                            "row {} has missing values",
+                            i
+                        ))));
+                    }
+
+                    let mut record = Record::new(
+                        columns.oid.value(i),
+                        columns.t.value(i),
+                        columns.lon.value(i),
+                        columns.lat.value(i),
+                    );
+                    record.sog = value(&columns.sog, i);
+                    record.cog = value(&columns.cog, i);
+                    record.heading = value(&columns.heading, i);
+                    record.nav_status = value(&columns.nav_status, i);
+                    record.ship_type = value(&columns.ship_type, i);
+                    return Some(Ok(record));
+                }
+            }
+
+            let batch = match self.batches.next()? {
+                Ok(batch) => batch,
+                Err(e) => return Some(Err(e.into())),
+            };
+            self.row = 0;
+            match self.columns(&batch) {
+                Ok(columns) => self.current = Some(columns),
+                Err(e) => {
+                    self.current = None;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+fn column<'a>(batch: &'a RecordBatch, name: &str) -> Result<&'a ArrayRef, MarshalError> {
+    batch
+        .column_by_name(name)
+        .ok_or_else(|| MarshalError::Config(format!("input has no column '{}'", name)))
+}
+
+fn optional_column<T>(
+    batch: &RecordBatch,
+    name: &str,
+    convert: fn(&ArrayRef) -> Result<T, MarshalError>,
+) -> Result<Option<T>, MarshalError> {
+    batch.column_by_name(name).map(convert).transpose()
+}
+
+// Null (or a missing column) -> None
+fn value<T: ArrowPrimitiveType>(array: &Option<PrimitiveArray<T>>, i: usize) -> Option<T::Native> {
+    array
+        .as_ref()
+        .filter(|array| array.is_valid(i))
+        .map(|array| array.value(i))
+}
+
+fn to_i32(array: &ArrayRef) -> Result<Int32Array, MarshalError> {
+    Ok(cast(array, &DataType::Int32)?
+        .as_any()
+        .downcast_ref::<Int32Array>()
+        .unwrap()
+        .clone())
+}
+
+fn to_f32(array: &ArrayRef) -> Result<Float32Array, MarshalError> {
+    Ok(cast(array, &DataType::Float32)?
+        .as_any()
+        .downcast_ref::<Float32Array>()
+        .unwrap()
+        .clone())
+}
+
+fn to_u8(array: &ArrayRef) -> Result<UInt8Array, MarshalError> {
+    Ok(cast(array, &DataType::UInt8)?
+        .as_any()
+        .downcast_ref::<UInt8Array>()
+        .unwrap()
+        .clone())
+}
+
+// Integer columns are taken as unix seconds, timestamp columns are converted from their unit
+fn to_seconds(array: &ArrayRef) -> Result<Int32Array, MarshalError> {
+    let per_second = match array.data_type() {
+        DataType::Timestamp(TimeUnit::Second, _) => 1,
+        DataType::Timestamp(TimeUnit::Millisecond, _) => 1_000,
+        DataType::Timestamp(TimeUnit::Microsecond, _) => 1_000_000,
+        DataType::Timestamp(TimeUnit::Nanosecond, _) => 1_000_000_000,
+        _ => return to_i32(array),
+    };
+
+    let raw = cast(array, &DataType::Int64)?;
+    let raw = raw.as_any().downcast_ref::<Int64Array>().unwrap();
+    Ok(raw
+        .iter()
+        .map(|t| t.and_then(|t| i32::try_from(t / per_second).ok()))
+        .collect())
+}
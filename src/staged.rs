@@ -0,0 +1,269 @@
+use crate::dataflow::{Input, Stage, StageSpec};
+use crate::pipeline::{EvictionClock, Pipeline};
+use crate::stats::QueueStats;
+use crate::structs::{Record, Trajectory, PREDICT_WINDOW};
+use crate::workers::{queue, Inbox, Link, WorkerOutput, Workers};
+use std::collections::HashMap;
+use std::sync::mpsc::{self, Iter, Receiver, RecvTimeoutError, Sender, TryIter};
+use std::thread::{self, JoinHandle};
+use std::time::Instant;
+
+enum Message {
+    Record {
+        record: Record,
+        index: usize, // in push order
+        due: Option<Instant>,
+        upstream: Vec<(Stage, Trajectory)>, // what the input stage emitted for the record
+        window: Option<Box<Trajectory>>, // for predict, the last points of the object in its input
+    },
+    Evict(i32), // event time to evict idle objects at
+}
+
+// Runs every stage of a dataflow on its own thread. Every record goes through the stages
+// that read the raw input and then, together with the points they emitted, through the
+// stages chained to them, over bounded queues: a stage that falls behind blocks its input
+// once its queue is full. Predict reads the trajectories of another stage, which sends it
+// the last points of the object along with every record
+pub struct StagedPipeline {
+    inputs: Vec<Link<Message>>, // the stages reading the raw input
+    outputs: Receiver<WorkerOutput>,
+    rejected: Option<Sender<WorkerOutput>>, // records no stage can take
+    workers: Vec<JoinHandle<(Pipeline, Vec<QueueStats>)>>,
+    queues: Vec<QueueStats>, // of the inputs, once they are closed
+    eviction: EvictionClock,
+    idle_timeout: i32,
+    pushed: usize,
+}
+
+impl StagedPipeline {
+    // `parts` run one stage each, see Dataflow::part, in dataflow order. Every stage reads
+    // its input through a queue of `queue_size` records
+    pub fn new(mut parts: Vec<Pipeline>, queue_size: usize) -> StagedPipeline {
+        let idle_timeout = parts[0].cfg.idle_timeout;
+        let specs: Vec<StageSpec> = parts.iter().map(|part| part.dataflow.stages[0]).collect();
+
+        let mut inputs = vec![];
+        let mut consumers: HashMap<Stage, Vec<(StageSpec, Link<Message>)>> = HashMap::new();
+        let mut inboxes = vec![];
+        for spec in specs.iter() {
+            let from = match spec.input {
+                Input::Raw => "input",
+                Input::Stage(input) => input.name(),
+            };
+            let (link, inbox) = queue(from, spec.stage.name(), queue_size);
+            match spec.input {
+                Input::Raw => inputs.push(link),
+                Input::Stage(input) => consumers.entry(input).or_default().push((*spec, link)),
+            }
+            inboxes.push(inbox);
+        }
+
+        let (sender, outputs) = mpsc::channel();
+        let mut workers = vec![];
+        for (mut part, inbox) in parts.drain(..).zip(inboxes) {
+            part.evict_on_push = false;
+            let worker = StageWorker {
+                spec: part.dataflow.stages[0],
+                consumers: consumers
+                    .remove(&part.dataflow.stages[0].stage)
+                    .unwrap_or_default(),
+                pipeline: part,
+                outputs: sender.clone(),
+            };
+            workers.push(
+                thread::Builder::new()
+                    .name(worker.spec.stage.name().to_string())
+                    .spawn(move || worker.run(inbox))
+                    .unwrap(),
+            );
+        }
+
+        StagedPipeline {
+            inputs,
+            outputs,
+            rejected: Some(sender),
+            workers,
+            queues: vec![],
+            eviction: EvictionClock::default(),
+            idle_timeout,
+            pushed: 0,
+        }
+    }
+}
+
+impl Workers for StagedPipeline {
+    fn push(&mut self, record: Record, due: Option<Instant>) {
+        let index = self.pushed;
+        self.pushed += 1;
+        // checked once here, not by every stage reading the raw input
+        if let Err(e) = record.validate() {
+            if let Some(rejected) = &self.rejected {
+                let _ = rejected.send(Err((Some(index), e)));
+            }
+            return;
+        }
+
+        let evict = self.eviction.tick(record.t, self.idle_timeout);
+        // a worker only hangs up when it panicked, join passes that on
+        for input in self.inputs.iter_mut() {
+            input.send(Message::Record {
+                record: record.clone(),
+                index,
+                due,
+                upstream: vec![],
+                window: None,
+            });
+            if let Some(now) = evict {
+                input.send(Message::Evict(now));
+            }
+        }
+    }
+
+    fn ready(&self) -> TryIter<'_, WorkerOutput> {
+        self.outputs.try_iter()
+    }
+
+    fn close(&mut self) -> Iter<'_, WorkerOutput> {
+        self.queues
+            .extend(self.inputs.drain(..).map(|input| input.stats));
+        self.rejected = None;
+        self.outputs.iter()
+    }
+
+    fn join(mut self: Box<Self>) -> Pipeline {
+        self.queues
+            .extend(self.inputs.drain(..).map(|input| input.stats));
+        let mut parts = self.workers.into_iter().map(|worker| match worker.join() {
+            Ok(part) => part,
+            Err(panic) => std::panic::resume_unwind(panic),
+        });
+
+        let (mut pipeline, queues) = parts.next().unwrap();
+        self.queues.extend(queues);
+        for (part, queues) in parts {
+            pipeline.merge_part(part);
+            self.queues.extend(queues);
+        }
+        pipeline.queues = self.queues;
+        pipeline
+    }
+}
+
+struct StageWorker {
+    spec: StageSpec,
+    pipeline: Pipeline,
+    consumers: Vec<(StageSpec, Link<Message>)>,
+    outputs: Sender<WorkerOutput>,
+}
+
+impl StageWorker {
+    fn run(mut self, mut inbox: Inbox<Message>) -> (Pipeline, Vec<QueueStats>) {
+        loop {
+            let message = match inbox.recv_until(self.pipeline.deadline()) {
+                Ok(message) => message,
+                // the prediction batch waited long enough for more records
+                Err(RecvTimeoutError::Timeout) => match self.pipeline.poll() {
+                    Ok(emitted) if emitted.is_empty() => continue,
+                    polled => {
+                        let polled = polled
+                            .map(|emitted| (emitted, vec![]))
+                            .map_err(|e| (None, e));
+                        if self.outputs.send(polled).is_err() {
+                            break;
+                        }
+                        continue;
+                    }
+                },
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+            match message {
+                Message::Record {
+                    record,
+                    index,
+                    due,
+                    upstream,
+                    window,
+                } => {
+                    if let (Stage::Predict, Input::Stage(input)) =
+                        (self.spec.stage, self.spec.input)
+                    {
+                        // the copy of the input trajectory predict reads
+                        let copy = self.pipeline.collections.entry(input).or_default();
+                        match window {
+                            Some(window) => copy.object.insert(record.oid, *window),
+                            None => copy.object.remove(&record.oid),
+                        };
+                    }
+
+                    let oid = record.oid;
+                    let emitted = match self.pipeline.push_after(record.clone(), upstream, due) {
+                        Ok(emitted) => emitted,
+                        Err(e) => {
+                            // like a single pipeline, the later stages skip the record. Other
+                            // stages reading the raw input may fail it too
+                            let _ = self.outputs.send(Err((Some(index), e)));
+                            continue;
+                        }
+                    };
+
+                    for (consumer, link) in self.consumers.iter_mut() {
+                        let window = match consumer.stage {
+                            Stage::Predict => self.pipeline.collections[&self.spec.stage]
+                                .object
+                                .get(&oid)
+                                .map(|traj| Box::new(traj.tail(PREDICT_WINDOW))),
+                            _ => None,
+                        };
+                        link.send(Message::Record {
+                            record: record.clone(),
+                            index,
+                            due,
+                            upstream: emitted.clone(),
+                            window,
+                        });
+                    }
+                    if !emitted.is_empty() && self.outputs.send(Ok((emitted, vec![]))).is_err() {
+                        break;
+                    }
+                }
+                Message::Evict(now) => {
+                    self.pipeline.evict_idle(now);
+                    if let (Stage::Predict, Input::Stage(input)) =
+                        (self.spec.stage, self.spec.input)
+                    {
+                        if let Some(copy) = self.pipeline.collections.get_mut(&input) {
+                            copy.evict_idle(now.saturating_sub(self.pipeline.cfg.idle_timeout));
+                        }
+                    }
+                    for (_, link) in self.consumers.iter_mut() {
+                        link.send(Message::Evict(now));
+                    }
+
+                    let evicted = self.pipeline.take_evicted();
+                    if !evicted.is_empty() && self.outputs.send(Ok((vec![], evicted))).is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+
+        match self.pipeline.flush() {
+            Ok(emitted) if emitted.is_empty() => {}
+            flushed => {
+                let _ = self.outputs.send(
+                    flushed
+                        .map(|emitted| (emitted, vec![]))
+                        .map_err(|e| (None, e)),
+                );
+            }
+        }
+
+        // hanging up ends the consumers too
+        let queues = self
+            .consumers
+            .into_iter()
+            .map(|(_, link)| link.stats)
+            .collect();
+        (self.pipeline, queues)
+    }
+}
@@ -0,0 +1,282 @@
+use hdrhistogram::Histogram;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+// Highest latency the histograms track, longer ones are clamped to it
+static MAX_LATENCY_NS: u64 = 60_000_000_000;
+
+// Per-record latencies of a stage in nanoseconds, kept in an HDR histogram with 3 significant
+// digits. The mean comes from the exact total, not from the buckets
+#[derive(Debug, Clone)]
+pub struct Latency {
+    histogram: Histogram<u64>,
+    total: u128,
+}
+
+impl Default for Latency {
+    fn default() -> Latency {
+        Latency::new()
+    }
+}
+
+impl Latency {
+    pub fn new() -> Latency {
+        Latency {
+            histogram: Histogram::new_with_bounds(1, MAX_LATENCY_NS, 3).unwrap(),
+            total: 0,
+        }
+    }
+
+    pub fn record(&mut self, latency: Duration) {
+        let ns = latency.as_nanos();
+        self.total += ns;
+        self.histogram
+            .saturating_record((ns as u64).clamp(1, MAX_LATENCY_NS));
+    }
+
+    pub fn merge(&mut self, other: &Latency) {
+        self.total += other.total;
+        // same bounds on both sides, adding cannot fail
+        self.histogram.add(&other.histogram).unwrap();
+    }
+
+    pub fn count(&self) -> u64 {
+        self.histogram.len()
+    }
+
+    // nanoseconds
+    pub fn total(&self) -> u128 {
+        self.total
+    }
+
+    pub fn mean(&self) -> f64 {
+        if self.count() == 0 {
+            0.0
+        } else {
+            self.total as f64 / self.count() as f64
+        }
+    }
+
+    // e.g. percentile(99.0)
+    pub fn percentile(&self, percentile: f64) -> u64 {
+        self.histogram.value_at_quantile(percentile / 100.0)
+    }
+
+    pub fn max(&self) -> u64 {
+        self.histogram.max()
+    }
+
+    pub fn summary(&self) -> LatencySummary {
+        LatencySummary {
+            count: self.count(),
+            mean_ns: self.mean(),
+            p50_ns: self.percentile(50.0),
+            p90_ns: self.percentile(90.0),
+            p99_ns: self.percentile(99.0),
+            max_ns: self.max(),
+        }
+    }
+}
+
+impl fmt::Display for Latency {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.summary().fmt(f)
+    }
+}
+
+// What the reports keep of a histogram
+#[derive(Debug, Clone, Serialize)]
+pub struct LatencySummary {
+    pub count: u64,
+    pub mean_ns: f64,
+    pub p50_ns: u64,
+    pub p90_ns: u64,
+    pub p99_ns: u64,
+    pub max_ns: u64,
+}
+
+// mean 12.30us, p50 10.10us, p90 20.20us, p99 40.40us, max 1.20ms
+impl fmt::Display for LatencySummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "mean {}, p50 {}, p90 {}, p99 {}, max {}",
+            human(self.mean_ns),
+            human(self.p50_ns as f64),
+            human(self.p90_ns as f64),
+            human(self.p99_ns as f64),
+            human(self.max_ns as f64)
+        )
+    }
+}
+
+fn human(ns: f64) -> String {
+    if ns >= 1e9 {
+        format!("{:.2}s", ns / 1e9)
+    } else if ns >= 1e6 {
+        format!("{:.2}ms", ns / 1e6)
+    } else {
+        format!("{:.2}us", ns / 1e3)
+    }
+}
+
+// Size of the state a stage keeps
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct StateSize {
+    pub objects: usize,
+    pub points: usize,
+    pub heap_bytes: usize,
+}
+
+// 76 objects, 9891 points, 1.21MB
+impl fmt::Display for StateSize {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} objects, {} points, {:.2}MB",
+            self.objects,
+            self.points,
+            self.heap_bytes as f64 / 1e6
+        )
+    }
+}
+
+// The state of every stage after `records` records
+#[derive(Debug, Clone, Serialize)]
+pub struct StateSample {
+    pub records: usize,
+    pub stages: BTreeMap<&'static str, StateSize>,
+}
+
+// How full a bounded queue between two threads was, sampled before every send
+#[derive(Debug, Clone, Serialize)]
+pub struct QueueStats {
+    pub from: String,
+    pub to: String,
+    pub capacity: usize,
+    pub sent: usize,
+    pub mean_depth: f64,
+    pub max_depth: usize,
+    pub full: usize,          // sends that had to wait for room (backpressure)
+    pub blocked_seconds: f64, // how long they waited
+    #[serde(skip)]
+    depth_sum: usize,
+}
+
+impl QueueStats {
+    pub fn new(from: &str, to: &str, capacity: usize) -> QueueStats {
+        QueueStats {
+            from: from.to_string(),
+            to: to.to_string(),
+            capacity,
+            sent: 0,
+            mean_depth: 0.0,
+            max_depth: 0,
+            full: 0,
+            blocked_seconds: 0.0,
+            depth_sum: 0,
+        }
+    }
+
+    // `depth` messages were waiting when one more was sent
+    pub fn observe(&mut self, depth: usize) {
+        // the receiver counts a message off just after taking it
+        let depth = depth.min(self.capacity);
+        self.sent += 1;
+        self.depth_sum += depth;
+        self.mean_depth = self.depth_sum as f64 / self.sent as f64;
+        self.max_depth = self.max_depth.max(depth);
+    }
+
+    pub fn blocked(&mut self, waited: Duration) {
+        self.full += 1;
+        self.blocked_seconds += waited.as_secs_f64();
+    }
+}
+
+// input -> clean: depth mean 3.20, max 64 of 64, 120 sends blocked for 12.30ms
+impl fmt::Display for QueueStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} -> {}: depth mean {:.2}, max {} of {}, {} sends blocked for {}",
+            self.from,
+            self.to,
+            self.mean_depth,
+            self.max_depth,
+            self.capacity,
+            self.full,
+            human(self.blocked_seconds * 1e9)
+        )
+    }
+}
+
+// Predictions run in batches (see predict_batch_size): how long the predictor took for a
+// batch and how long a trajectory waited, from its record to its prediction
+#[derive(Debug, Clone, Default)]
+pub struct BatchStats {
+    pub batches: usize,
+    pub windows: usize,
+    pub max_size: usize,
+    pub forward: Latency, // per batch
+    pub waited: Latency,  // per window
+}
+
+impl BatchStats {
+    // A batch that took `forward`, with the times its windows were queued at
+    pub fn record(&mut self, forward: Duration, queued: &[Instant]) {
+        self.batches += 1;
+        self.windows += queued.len();
+        self.max_size = self.max_size.max(queued.len());
+        self.forward.record(forward);
+        for queued in queued.iter() {
+            self.waited.record(queued.elapsed());
+        }
+    }
+
+    pub fn merge(&mut self, other: &BatchStats) {
+        self.batches += other.batches;
+        self.windows += other.windows;
+        self.max_size = self.max_size.max(other.max_size);
+        self.forward.merge(&other.forward);
+        self.waited.merge(&other.waited);
+    }
+
+    pub fn summary(&self) -> BatchSummary {
+        BatchSummary {
+            batches: self.batches,
+            mean_size: self.windows as f64 / self.batches.max(1) as f64,
+            max_size: self.max_size,
+            windows_per_second: match self.forward.total() {
+                0 => 0.0,
+                ns => self.windows as f64 / (ns as f64 / 1e9),
+            },
+            forward: self.forward.summary(),
+            waited: self.waited.summary(),
+        }
+    }
+}
+
+// What the reports keep of the batches
+#[derive(Debug, Clone, Serialize)]
+pub struct BatchSummary {
+    pub batches: usize,
+    pub mean_size: f64,
+    pub max_size: usize,
+    pub windows_per_second: f64, // of time spent in the predictor
+    pub forward: LatencySummary,
+    pub waited: LatencySummary,
+}
+
+// 812 batches of 12.3 windows (max 16), 9120 windows/s
+impl fmt::Display for BatchSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} batches of {:.1} windows (max {}), {:.0} windows/s",
+            self.batches, self.mean_size, self.max_size, self.windows_per_second
+        )
+    }
+}
@@ -1,255 +1,348 @@
+use crate::config::PipelineConfig;
 use crate::structs::{Coordinate, Pois, Record, TrajCollection, Trajectory};
 
 // use std::{thread, time}
 
-fn perc_diff(sp1: f32, br1: f32, sp2: f32, br2: f32) -> bool {
-    let sp_diff = (sp2 - sp1).abs() / crate::MAX_SPEED;
-    let br_diff = (br2 - br1).abs() / 180.0;
-
-    sp_diff > crate::COMP_THR || br_diff > crate::COMP_THR
+// What an operator produces for a single record
+pub struct Emitted {
+    pub points: Trajectory,
+    pub flush: Option<usize>, // eviction hint: how many of the oldest stored points can be dropped
 }
 
-pub fn resampled(record: Record, traj_coll: &TrajCollection, pois: &Pois) -> Trajectory {
-    if !traj_coll.object.contains_key(&record.oid) {
-        let new_traj = Trajectory::new(
-            record.oid,
-            crate::HISTORY_SIZE,
-            Coordinate {
-                x: record.lon,
-                y: record.lat,
-            },
-            record.t,
-        );
-        // new_traj.to_csv();
-        return new_traj;
+impl Emitted {
+    pub fn points(points: Trajectory) -> Emitted {
+        Emitted {
+            points,
+            flush: None,
+        }
     }
+}
 
-    let oid_traj = traj_coll.object.get(&record.oid).unwrap();
-
-    let mut new_traj = Trajectory::new_empty(record.oid, crate::HISTORY_SIZE);
-    let coord = Coordinate {
-        x: record.lon,
-        y: record.lat,
-    };
-
-    if (record.t == oid_traj.timestamps.last().unwrap().to_owned())
-        | (record.t - oid_traj.timestamps.last().unwrap() < crate::RATE)
-    {
-        return new_traj;
-    };
-
-    // if oid_traj.can_skip(100, coord.clone(), timestamp, oid_traj.speed.last().unwrap().clone(), oid_traj.bearing.last().unwrap().clone()){return}
-
-    let speed_now = oid_traj.calculate_speed(&coord, &record.t);
-    let bearing_now = oid_traj.calculate_bearing(&coord);
-
-    if speed_now > crate::MAX_SPEED {
-        return new_traj;
-    };
-
-    let new_coords = oid_traj.resample(crate::RATE, &record.t, speed_now, bearing_now);
+// Send, so the pipeline of a shard can be moved to its worker thread
+pub trait StreamOperator: Send {
+    fn name(&self) -> &'static str;
 
-    for (new_coord, timestamp) in &new_coords {
-        let is_stoped = if speed_now < crate::STOP_SPEED_THR {
-            1
-        } else {
-            0
-        };
-        // let poi_id = -1;
-        let poi_id = if is_stoped == 1 && oid_traj.stoped.last().unwrap().to_owned() == 1 {
-            oid_traj.pois.last().unwrap().to_owned()
-        } else if is_stoped == 1 {
-            pois.nearest(&new_coord, crate::DISTANCE_TO_POI_THR)
-        } else {
-            -1
-        };
-
-        let trip_id = if oid_traj.stoped.last().unwrap().to_owned() == 1 && is_stoped != 1 {
-            oid_traj.trips.last().unwrap() + 1
-        } else {
-            oid_traj.trips.last().unwrap().to_owned()
-        };
+    // Whether apply looks at objects other than the one of the record (e.g. flocks), then
+    // the shards of a parallel run share a FlockIndex for this stage
+    fn reads_other_objects(&self) -> bool {
+        false
+    }
 
-        // let gps: Vec<i32> = traj.flocks(record.oid, flocks_distance_threshold, timestamp, speed_now, bearing_now);
+    // Whether the flush hints of apply come from a compression (OPW), then the first point
+    // of an object and every point a flush makes the new anchor are the ones it keeps
+    fn compresses(&self) -> bool {
+        false
+    }
 
-        // eprintln!("hey {:?}", new_traj);
-        let flocked_oids = traj_coll.flocks(
-            &coord,
-            speed_now,
-            bearing_now,
-            timestamp.to_owned(),
-            record.oid,
-        );
+    // Points that `record` adds to an object we have already seen. `oid_traj` is its stored
+    // trajectory, `traj_coll` the state of every object (read only, e.g. for flocks)
+    fn apply(
+        &self,
+        record: Record,
+        oid_traj: &Trajectory,
+        traj_coll: &TrajCollection,
+        pois: &Pois,
+        cfg: &PipelineConfig,
+    ) -> Emitted;
+
+    fn process(
+        &self,
+        record: Record,
+        traj_coll: &TrajCollection,
+        pois: &Pois,
+        cfg: &PipelineConfig,
+    ) -> Emitted {
+        match traj_coll.object.get(&record.oid) {
+            Some(oid_traj) => self.apply(record, oid_traj, traj_coll, pois, cfg),
+            None => Emitted::points(first_point(&record, cfg)),
+        }
+    }
 
-        new_traj.insert_unbounded(
-            new_coord.clone(),
-            timestamp.to_owned(),
-            speed_now,
-            bearing_now,
-            poi_id,
-            trip_id,
-            is_stoped,
-            flocked_oids,
-        );
+    // Runs the operator and updates its state, returns the emitted points and flush hint
+    fn push(
+        &self,
+        record: Record,
+        traj_coll: &mut TrajCollection,
+        pois: &Pois,
+        cfg: &PipelineConfig,
+    ) -> Emitted {
+        let emitted = self.process(record, traj_coll, pois, cfg);
+        let points = emitted.points.clone();
+        traj_coll.extend_flush(emitted.points, emitted.flush);
+        if cfg.history_window > 0 {
+            if let Some(traj) = traj_coll.object.get_mut(&points.oid) {
+                traj.retain_window(cfg.history_window);
+            }
+        }
+        traj_coll.publish(points.oid);
+        Emitted {
+            points,
+            flush: emitted.flush,
+        }
     }
-    // new_traj.to_csv();
-    return new_traj;
 }
 
-pub fn compressed(
-    record: Record,
-    traj_coll: &TrajCollection,
-    pois: &Pois,
-) -> (Trajectory, Option<usize>) {
-    if !traj_coll.object.contains_key(&record.oid) {
-        let new_traj = Trajectory::new(
-            record.oid,
-            crate::HISTORY_SIZE,
-            Coordinate {
-                x: record.lon,
-                y: record.lat,
-            },
-            record.t,
-        );
-        // new_traj.to_csv();
-        return (new_traj, None);
-    }
+// The first record of an object starts its trajectory as is
+pub fn first_point(record: &Record, cfg: &PipelineConfig) -> Trajectory {
+    let mut traj = Trajectory::new(
+        record.oid,
+        cfg.history_size,
+        Coordinate {
+            x: record.lon,
+            y: record.lat,
+        },
+        record.t,
+    );
+    traj.reported[0] = record.reported();
+    traj
+}
 
-    let oid_traj = traj_coll.object.get(&record.oid).unwrap();
+pub struct Motion {
+    pub coord: Coordinate,
+    pub speed: f32,
+    pub bearing: f32,
+}
 
-    let mut new_traj = Trajectory::new_empty(record.oid, crate::HISTORY_SIZE);
+// Speed and bearing of a new fix. They are derived from the last stored point, unless the
+// record reports its own SOG/COG and cfg.prefer_reported is set. None for duplicate
+// timestamps, for fixes that would need a speed above max_speed and for fixes whose derived
+// speed is further than cfg.speed_mismatch_thr from the reported one (a position jump)
+pub fn motion(record: &Record, oid_traj: &Trajectory, cfg: &PipelineConfig) -> Option<Motion> {
     let coord = Coordinate {
         x: record.lon,
         y: record.lat,
     };
 
-    if record.t == oid_traj.timestamps.last().unwrap().to_owned() {
-        return (new_traj, None);
+    if record.t == *oid_traj.timestamps.last().unwrap() {
+        return None;
     };
 
     // if oid_traj.can_skip(100, coord.clone(), timestamp, oid_traj.speed.last().unwrap().clone(), oid_traj.bearing.last().unwrap().clone()){return}
 
-    let speed_now = oid_traj.calculate_speed(&coord, &record.t);
-    let bearing_now = oid_traj.calculate_bearing(&coord);
+    let speed = oid_traj.calculate_speed(&coord, &record.t);
+    let bearing = oid_traj.calculate_bearing(&coord);
 
-    if speed_now > crate::MAX_SPEED {
-        return (new_traj, None);
+    if speed > cfg.max_speed {
+        return None;
     };
 
-    let return_id = oid_traj.OPW_TR(&coord, record.t);
-
-    // match return_id {
-    //     Some(return_id_usize) => {
-    //         oid_traj.print_row(return_id_usize);
-    //     },
-    //     _ => ()
-    // }
+    if let Some(sog) = record.sog {
+        if cfg.speed_mismatch_thr > 0.0 && (speed - sog).abs() > cfg.speed_mismatch_thr {
+            return None;
+        }
+    }
 
-    let is_stoped = if speed_now < crate::STOP_SPEED_THR {
-        1
+    let (speed, bearing) = if cfg.prefer_reported {
+        (
+            record.sog.unwrap_or(speed),
+            // COG is 0..360, derived bearings are -180..180
+            record
+                .cog
+                .map_or(bearing, |cog| if cog > 180.0 { cog - 360.0 } else { cog }),
+        )
     } else {
-        0
+        (speed, bearing)
     };
-    // let poi_id = -1;
-    let poi_id = if is_stoped == 1 && oid_traj.stoped.last().unwrap().to_owned() == 1 {
+
+    Some(Motion {
+        coord,
+        speed,
+        bearing,
+    })
+}
+
+pub struct Annotation {
+    pub stoped: i8,
+    pub poi_id: i32,
+    pub trip_id: i32,
+}
+
+// Stop flag, nearest POI and trip of a new point given the last state of its object
+pub fn annotate(
+    oid_traj: &Trajectory,
+    coord: &Coordinate,
+    speed: f32,
+    pois: &Pois,
+    cfg: &PipelineConfig,
+) -> Annotation {
+    let is_stoped = if speed < cfg.stop_speed_thr { 1 } else { 0 };
+
+    let poi_id = if is_stoped == 1 && *oid_traj.stoped.last().unwrap() == 1 {
         oid_traj.pois.last().unwrap().to_owned()
     } else if is_stoped == 1 {
-        pois.nearest(&coord, crate::DISTANCE_TO_POI_THR)
+        pois.nearest(coord, cfg.distance_to_poi_thr)
     } else {
         -1
     };
-    let trip_id = if oid_traj.stoped.last().unwrap().to_owned() == 1 && is_stoped != 1 {
+
+    let trip_id = if *oid_traj.stoped.last().unwrap() == 1 && is_stoped != 1 {
         oid_traj.trips.last().unwrap() + 1
     } else {
         oid_traj.trips.last().unwrap().to_owned()
     };
 
-    // let gps: Vec<i32> = traj.flocks(record.oid, flocks_distance_threshold, timestamp, speed_now, bearing_now);
-    // eprintln!("hey {:?}", new_traj);
-    let flocked_oids = vec![];
-
-    new_traj.insert_unbounded(
-        coord.clone(),
-        record.t,
-        speed_now,
-        bearing_now,
+    Annotation {
+        stoped: is_stoped,
         poi_id,
         trip_id,
-        is_stoped,
-        flocked_oids,
-    );
+    }
+}
+
+pub struct Resampled;
+
+impl StreamOperator for Resampled {
+    fn name(&self) -> &'static str {
+        "resample"
+    }
 
-    return (new_traj, return_id);
+    fn reads_other_objects(&self) -> bool {
+        true
+    }
+
+    fn apply(
+        &self,
+        record: Record,
+        oid_traj: &Trajectory,
+        traj_coll: &TrajCollection,
+        pois: &Pois,
+        cfg: &PipelineConfig,
+    ) -> Emitted {
+        let mut new_traj = Trajectory::new_empty(record.oid, cfg.history_size);
+
+        if record.t - oid_traj.timestamps.last().unwrap() < cfg.rate {
+            return Emitted::points(new_traj);
+        };
+
+        let motion = match motion(&record, oid_traj, cfg) {
+            Some(motion) => motion,
+            None => return Emitted::points(new_traj),
+        };
+
+        let new_coords = oid_traj.resample(cfg.rate, &record.t, motion.speed, motion.bearing);
+
+        for (new_coord, timestamp) in &new_coords {
+            let annotation = annotate(oid_traj, new_coord, motion.speed, pois, cfg);
+
+            // let gps: Vec<i32> = traj.flocks(record.oid, flocks_distance_threshold, timestamp, speed_now, bearing_now);
+
+            let flocked_oids = traj_coll.flocks(
+                &motion.coord,
+                motion.speed,
+                motion.bearing,
+                timestamp.to_owned(),
+                record.oid,
+                cfg,
+            );
+
+            new_traj.insert_unbounded(
+                new_coord.clone(),
+                timestamp.to_owned(),
+                motion.speed,
+                motion.bearing,
+                annotation.poi_id,
+                annotation.trip_id,
+                annotation.stoped,
+                flocked_oids,
+                record.reported(),
+            );
+        }
+        // new_traj.to_csv();
+        Emitted::points(new_traj)
+    }
 }
 
-pub fn cleaned(record: Record, traj_coll: &TrajCollection, pois: &Pois) -> Trajectory {
-    if !traj_coll.object.contains_key(&record.oid) {
-        return Trajectory::new(
-            record.oid,
-            crate::HISTORY_SIZE,
-            Coordinate {
-                x: record.lon,
-                y: record.lat,
-            },
-            record.t,
-        );
+pub struct Compressed;
+
+impl StreamOperator for Compressed {
+    fn name(&self) -> &'static str {
+        "compress"
     }
 
-    let oid_traj = traj_coll.object.get(&record.oid).unwrap();
+    fn compresses(&self) -> bool {
+        true
+    }
 
-    let mut new_traj = Trajectory::new_empty(record.oid, crate::HISTORY_SIZE);
-    let coord = Coordinate {
-        x: record.lon,
-        y: record.lat,
-    };
+    fn apply(
+        &self,
+        record: Record,
+        oid_traj: &Trajectory,
+        _traj_coll: &TrajCollection,
+        pois: &Pois,
+        cfg: &PipelineConfig,
+    ) -> Emitted {
+        let mut new_traj = Trajectory::new_empty(record.oid, cfg.history_size);
+
+        let motion = match motion(&record, oid_traj, cfg) {
+            Some(motion) => motion,
+            None => return Emitted::points(new_traj),
+        };
 
-    if record.t == oid_traj.timestamps.last().unwrap().to_owned() {
-        return new_traj;
-    };
+        let return_id = oid_traj.opw_tr(&motion.coord, record.t, cfg.opw_epsilon);
 
-    // if oid_traj.can_skip(100, coord.clone(), timestamp, oid_traj.speed.last().unwrap().clone(), oid_traj.bearing.last().unwrap().clone()){return}
+        // match return_id {
+        //     Some(return_id_usize) => {
+        //         oid_traj.print_row(return_id_usize);
+        //     },
+        //     _ => ()
+        // }
 
-    let speed_now = oid_traj.calculate_speed(&coord, &record.t);
-    let bearing_now = oid_traj.calculate_bearing(&coord);
+        let annotation = annotate(oid_traj, &motion.coord, motion.speed, pois, cfg);
 
-    if speed_now > crate::MAX_SPEED {
-        return new_traj;
-    };
+        new_traj.insert_unbounded(
+            motion.coord,
+            record.t,
+            motion.speed,
+            motion.bearing,
+            annotation.poi_id,
+            annotation.trip_id,
+            annotation.stoped,
+            vec![],
+            record.reported(),
+        );
 
-    let is_stoped = if speed_now < crate::STOP_SPEED_THR {
-        1
-    } else {
-        0
-    };
+        Emitted {
+            points: new_traj,
+            flush: return_id,
+        }
+    }
+}
 
-    let poi_id = if is_stoped == 1 && oid_traj.stoped.last().unwrap().to_owned() == 1 {
-        oid_traj.pois.last().unwrap().to_owned()
-    } else if is_stoped == 1 {
-        pois.nearest(&coord, crate::DISTANCE_TO_POI_THR)
-    } else {
-        -1
-    };
+pub struct Cleaned;
 
-    let trip_id = if oid_traj.stoped.last().unwrap().to_owned() == 1 && is_stoped != 1 {
-        oid_traj.trips.last().unwrap() + 1
-    } else {
-        oid_traj.trips.last().unwrap().to_owned()
-    };
+impl StreamOperator for Cleaned {
+    fn name(&self) -> &'static str {
+        "clean"
+    }
 
-    // let gps: Vec<i32> = traj.flocks(record.oid, flocks_distance_threshold, timestamp, speed_now, bearing_now);
-    // eprintln!("hey {:?}", new_traj);
-    let flocked_oids = vec![];
+    fn apply(
+        &self,
+        record: Record,
+        oid_traj: &Trajectory,
+        _traj_coll: &TrajCollection,
+        pois: &Pois,
+        cfg: &PipelineConfig,
+    ) -> Emitted {
+        let mut new_traj = Trajectory::new_empty(record.oid, cfg.history_size);
+
+        let motion = match motion(&record, oid_traj, cfg) {
+            Some(motion) => motion,
+            None => return Emitted::points(new_traj),
+        };
 
-    new_traj.insert_unbounded(
-        coord.clone(),
-        record.t,
-        speed_now,
-        bearing_now,
-        poi_id,
-        trip_id,
-        is_stoped,
-        flocked_oids,
-    );
+        let annotation = annotate(oid_traj, &motion.coord, motion.speed, pois, cfg);
+
+        new_traj.insert_unbounded(
+            motion.coord,
+            record.t,
+            motion.speed,
+            motion.bearing,
+            annotation.poi_id,
+            annotation.trip_id,
+            annotation.stoped,
+            vec![],
+            record.reported(),
+        );
 
-    return new_traj;
+        Emitted::points(new_traj)
+    }
 }
@@ -1,17 +1,81 @@
+use crate::config::PipelineConfig;
+use crate::error::MarshalError;
+use crate::predictor::{self, Predictor};
+use crate::stats::StateSize;
 use itertools::izip;
 use libm::atan2f;
 use proj::Proj;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::collections::hash_map::Entry;
 use std::collections::HashMap;
-use tch::{CModule, Tensor};
+use std::mem::size_of;
+use std::sync::{Arc, RwLock};
 
-#[derive(Deserialize, Clone)]
+// Points of an object the model looks at for a prediction
+pub const PREDICT_WINDOW: usize = 13;
+
+#[derive(Serialize, Deserialize, Clone)]
 pub struct Record {
     pub oid: i32,
     pub t: i32,
     pub lon: f32,
     pub lat: f32,
+    // reported by the vessel, when the input carries them (e.g. decoded AIS)
+    #[serde(default)]
+    pub sog: Option<f32>, // knots
+    #[serde(default)]
+    pub cog: Option<f32>, // degrees
+    #[serde(default)]
+    pub heading: Option<f32>, // degrees
+    #[serde(default)]
+    pub nav_status: Option<u8>, // AIS navigational status, 0 under way .. 14
+    #[serde(default)]
+    pub ship_type: Option<u8>, // AIS ship and cargo type
+}
+
+impl Record {
+    pub fn new(oid: i32, t: i32, lon: f32, lat: f32) -> Record {
+        Record {
+            oid,
+            t,
+            lon,
+            lat,
+            sog: None,
+            cog: None,
+            heading: None,
+            nav_status: None,
+            ship_type: None,
+        }
+    }
+
+    pub fn reported(&self) -> Reported {
+        Reported {
+            sog: self.sog,
+            cog: self.cog,
+            heading: self.heading,
+            nav_status: self.nav_status,
+            ship_type: self.ship_type,
+        }
+    }
+
+    pub fn with_reported(mut self, reported: &Reported) -> Record {
+        self.sog = reported.sog;
+        self.cog = reported.cog;
+        self.heading = reported.heading;
+        self.nav_status = reported.nav_status;
+        self.ship_type = reported.ship_type;
+        self
+    }
+
+    pub fn validate(&self) -> Result<(), MarshalError> {
+        if !(-180.0..=180.0).contains(&self.lon) || !(-90.0..=90.0).contains(&self.lat) {
+            return Err(MarshalError::InvalidRecord(format!(
+                "{} has coordinates out of range",
+                self
+            )));
+        }
+        Ok(())
+    }
 }
 
 impl std::fmt::Display for Record {
@@ -24,14 +88,24 @@ impl std::fmt::Display for Record {
     }
 }
 
-#[derive(Debug, Clone, Deserialize)]
+// The optional Record fields, kept next to every point of a trajectory
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct Reported {
+    pub sog: Option<f32>,
+    pub cog: Option<f32>,
+    pub heading: Option<f32>,
+    pub nav_status: Option<u8>,
+    pub ship_type: Option<u8>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Coordinate {
     pub x: f32,
     pub y: f32,
 }
 impl Coordinate {
     pub fn haversine(&self, coord: &Coordinate) -> f32 {
-        let R = 6371000.0;
+        let r = 6371000.0;
         let d_lat = (coord.y - self.y).to_radians();
         let d_lon = (coord.x - self.x).to_radians();
 
@@ -42,7 +116,7 @@ impl Coordinate {
                 * (coord.y.to_radians().cos());
         let c: f32 = 2.0 * ((a.sqrt()).atan2((1.0 - a).sqrt()));
 
-        R * c / 1852.0 // returns nautical miles
+        r * c / 1852.0 // returns nautical miles
     }
 
     pub fn from_tuple(tup: (f32, f32)) -> Coordinate {
@@ -82,13 +156,13 @@ impl Coordinate {
         }
     }
 
-    pub fn project(&self, from: &str, to: &str) -> Coordinate {
-        let ft_to_m = Proj::new_known_crs(&from, &to, None).unwrap();
-        Coordinate::from_tuple(ft_to_m.convert((self.x, self.y)).unwrap())
+    pub fn project(&self, from: &str, to: &str) -> Result<Coordinate, MarshalError> {
+        let ft_to_m = Proj::new_known_crs(from, to, None)?;
+        Ok(Coordinate::from_tuple(ft_to_m.convert((self.x, self.y))?))
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Trajectory {
     pub oid: i32,
     pub max_size: usize,
@@ -100,6 +174,7 @@ pub struct Trajectory {
     pub trips: Vec<i32>,
     pub pois: Vec<i32>,
     pub gps: Vec<Vec<i32>>,
+    pub reported: Vec<Reported>,
 }
 
 impl Trajectory {
@@ -115,6 +190,7 @@ impl Trajectory {
             trips: vec![0],
             pois: vec![-1],
             gps: vec![vec![]],
+            reported: vec![Reported::default()],
         }
     }
 
@@ -130,8 +206,10 @@ impl Trajectory {
             trips: vec![],
             pois: vec![],
             gps: vec![],
+            reported: vec![],
         }
     }
+    #[allow(clippy::too_many_arguments)]
     pub fn insert_unbounded(
         &mut self,
         coord: Coordinate,
@@ -142,6 +220,7 @@ impl Trajectory {
         trip_id: i32,
         stoped: i8,
         gps: Vec<i32>,
+        reported: Reported,
     ) {
         self.speed.push(sp);
         self.bearing.push(br);
@@ -151,6 +230,7 @@ impl Trajectory {
         self.stoped.push(stoped);
         self.trips.push(trip_id);
         self.gps.push(gps);
+        self.reported.push(reported);
     }
 
     pub fn extend(&mut self, trajectory: Trajectory) {
@@ -162,6 +242,7 @@ impl Trajectory {
         self.stoped.extend(trajectory.stoped);
         self.trips.extend(trajectory.trips);
         self.gps.extend(trajectory.gps);
+        self.reported.extend(trajectory.reported);
 
         let size = self.speed.len();
 
@@ -174,6 +255,7 @@ impl Trajectory {
             self.trips.drain(0..size - self.max_size);
             self.pois.drain(0..size - self.max_size);
             self.gps.drain(0..size - self.max_size);
+            self.reported.drain(0..size - self.max_size);
         }
     }
 
@@ -186,26 +268,89 @@ impl Trajectory {
         self.trips.drain(0..n);
         self.pois.drain(0..n);
         self.gps.drain(0..n);
+        self.reported.drain(0..n);
+    }
+
+    // Drops the points more than `window` seconds older than the newest one, which is
+    // always kept
+    pub fn retain_window(&mut self, window: i32) {
+        let newest = match self.timestamps.last() {
+            Some(t) => *t,
+            None => return,
+        };
+        let expired = self
+            .timestamps
+            .partition_point(|t| *t < newest.saturating_sub(window));
+        if expired > 0 {
+            self.drop_first_n(expired.min(self.timestamps.len() - 1));
+        }
+    }
+
+    // The last `n` points
+    pub fn tail(&self, n: usize) -> Trajectory {
+        let start = self.timestamps.len().saturating_sub(n);
+        Trajectory {
+            oid: self.oid,
+            max_size: self.max_size,
+            coordinates: self.coordinates[start..].to_vec(),
+            timestamps: self.timestamps[start..].to_vec(),
+            speed: self.speed[start..].to_vec(),
+            bearing: self.bearing[start..].to_vec(),
+            stoped: self.stoped[start..].to_vec(),
+            trips: self.trips[start..].to_vec(),
+            pois: self.pois[start..].to_vec(),
+            gps: self.gps[start..].to_vec(),
+            reported: self.reported[start..].to_vec(),
+        }
+    }
+
+    // Bytes the point columns hold on the heap (by capacity, not length)
+    pub fn heap_bytes(&self) -> usize {
+        self.coordinates.capacity() * size_of::<Coordinate>()
+            + self.timestamps.capacity() * size_of::<i32>()
+            + self.speed.capacity() * size_of::<f32>()
+            + self.bearing.capacity() * size_of::<f32>()
+            + self.stoped.capacity() * size_of::<i8>()
+            + self.trips.capacity() * size_of::<i32>()
+            + self.pois.capacity() * size_of::<i32>()
+            + self.gps.capacity() * size_of::<Vec<i32>>()
+            + self.gps.iter().map(|oids| oids.capacity()).sum::<usize>() * size_of::<i32>()
+            + self.reported.capacity() * size_of::<Reported>()
     }
 
     pub fn to_csv(&self) {
         for i in 0..self.speed.len() {
-            println!(
-                "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{:?}",
-                self.oid,
-                self.coordinates[i].x,
-                self.coordinates[i].y,
-                self.speed[i],
-                self.bearing[i],
-                self.stoped[i],
-                self.trips[i],
-                self.timestamps[i],
-                self.pois[i],
-                self.gps[i]
-            )
+            println!("{}", self.csv_row(i))
         }
     }
 
+    // oid,lon,lat,speed,bearing,stoped,trip,timestamp,poi_id,gps with the flocked oids joined by ';'
+    pub fn csv_row(&self, i: usize) -> String {
+        let gps: Vec<String> = self.gps[i].iter().map(|oid| oid.to_string()).collect();
+        format!(
+            "{},{},{},{},{},{},{},{},{},{}",
+            self.oid,
+            self.coordinates[i].x,
+            self.coordinates[i].y,
+            self.speed[i],
+            self.bearing[i],
+            self.stoped[i],
+            self.trips[i],
+            self.timestamps[i],
+            self.pois[i],
+            gps.join(";")
+        )
+    }
+
+    // Turns the points of a stage output back into records, so another stage can consume them
+    pub fn to_records(&self) -> Vec<Record> {
+        izip!(&self.coordinates, &self.timestamps, &self.reported)
+            .map(|(coord, t, reported)| {
+                Record::new(self.oid, t.to_owned(), coord.x, coord.y).with_reported(reported)
+            })
+            .collect()
+    }
+
     pub fn print_row(&self, i: usize) {
         println!(
             "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{:?}",
@@ -273,8 +418,8 @@ impl Trajectory {
         )
     }
 
-    pub fn OPW_TR(&self, coord: &Coordinate, timestamp: i32) -> Option<usize> {
-        fn _calc_SED(
+    pub fn opw_tr(&self, coord: &Coordinate, timestamp: i32, epsilon: f32) -> Option<usize> {
+        fn calc_sed(
             pnt_s: &Coordinate,
             ts_s: i32,
             pnt_m: &Coordinate,
@@ -306,15 +451,15 @@ impl Trajectory {
             .zip(self.timestamps[1..].iter())
             .enumerate()
         {
-            let err_sed = _calc_SED(
+            let err_sed = calc_sed(
                 self.coordinates.first().unwrap(),
-                self.timestamps.first().unwrap().clone(),
+                *self.timestamps.first().unwrap(),
                 mid_coord,
                 mid_ts.to_owned(),
                 coord,
-                timestamp.clone(),
+                timestamp,
             );
-            if err_sed > crate::OPW_EPSILON {
+            if err_sed > epsilon {
                 return Some(mid_id + 1);
             }
         }
@@ -328,13 +473,46 @@ impl Trajectory {
         //         else
         //             i++;
         //     }
-        return None;
+        None
     }
 }
 
+// The last point of an object, all that flocks looks at
 #[derive(Debug, Clone)]
+pub struct Head {
+    pub coord: Coordinate,
+    pub t: i32,
+    pub speed: f32,
+    pub bearing: f32,
+    pub stoped: i8,
+}
+
+impl Head {
+    pub fn of(traj: &Trajectory) -> Option<Head> {
+        Some(Head {
+            coord: traj.coordinates.last()?.clone(),
+            t: *traj.timestamps.last()?,
+            speed: *traj.speed.last()?,
+            bearing: *traj.bearing.last()?,
+            stoped: *traj.stoped.last()?,
+        })
+    }
+
+    // same as Trajectory::extrapolate_next
+    pub fn extrapolate(&self, dt: i32) -> Coordinate {
+        self.coord.extrapolate(self.speed, self.bearing, dt)
+    }
+}
+
+// The heads of every object of a stage when its objects are split over several shards, so
+// flocks also finds the objects of the other shards
+pub type FlockIndex = Arc<RwLock<HashMap<i32, Head>>>;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct TrajCollection {
     pub object: HashMap<i32, Trajectory>,
+    #[serde(skip)]
+    pub flock_index: Option<FlockIndex>,
 }
 
 impl std::fmt::Display for TrajCollection {
@@ -344,6 +522,21 @@ impl std::fmt::Display for TrajCollection {
 }
 
 impl TrajCollection {
+    // Objects, stored points and an approximation of the heap bytes behind them: the point
+    // columns plus a key, a value and a control byte per bucket of the hash table
+    pub fn state_size(&self) -> StateSize {
+        StateSize {
+            objects: self.object.len(),
+            points: self.object.values().map(|traj| traj.timestamps.len()).sum(),
+            heap_bytes: self.object.capacity() * (size_of::<i32>() + size_of::<Trajectory>() + 1)
+                + self
+                    .object
+                    .values()
+                    .map(|traj| traj.heap_bytes())
+                    .sum::<usize>(),
+        }
+    }
+
     // pub fn append(&mut self, record: Record){
     //     match self.object.entry(record.oid) {
     //         Entry::Vacant(e) => { e.insert(Trajectory::new(record.oid, Coordinate{x: record.lon, y: record.lat}, record.t)); },
@@ -358,14 +551,44 @@ impl TrajCollection {
             }
             Entry::Occupied(mut e) => {
                 e.get_mut().extend(trajectory);
-                match n_opt {
-                    Some(n) => e.get_mut().drop_first_n(n),
-                    _ => (),
+                if let Some(n) = n_opt {
+                    e.get_mut().drop_first_n(n);
                 }
             }
         }
     }
 
+    // Removes the objects whose last point is older than `before`, ordered by oid
+    pub fn evict_idle(&mut self, before: i32) -> Vec<Trajectory> {
+        let mut idle: Vec<i32> = self
+            .object
+            .iter()
+            .filter(|(_, traj)| traj.timestamps.last().is_none_or(|t| *t < before))
+            .map(|(oid, _)| *oid)
+            .collect();
+        idle.sort_unstable();
+        let evicted: Vec<Trajectory> = idle
+            .iter()
+            .filter_map(|oid| self.object.remove(oid))
+            .collect();
+        for traj in evicted.iter() {
+            self.publish(traj.oid);
+        }
+        evicted
+    }
+
+    // Updates the head of `oid` in the flock index, if the collection shares one
+    pub fn publish(&self, oid: i32) {
+        if let Some(index) = &self.flock_index {
+            let head = self.object.get(&oid).and_then(Head::of);
+            let mut index = index.write().unwrap();
+            match head {
+                Some(head) => index.insert(oid, head),
+                None => index.remove(&oid),
+            };
+        }
+    }
+
     pub fn pretty(&self) {
         for (_, trajec) in self.object.clone().into_iter() {
             for i in 0..trajec.speed.len() {
@@ -384,26 +607,13 @@ impl TrajCollection {
     }
 
     pub fn to_csv(&self) {
-        println!("oid,lon,lat,speed,bearing,stoped,trip,timestamp,poi_id");
-        for (o_id, trajec) in self.object.clone().into_iter() {
-            for i in 0..trajec.speed.len() {
-                println!(
-                    "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
-                    o_id,
-                    trajec.coordinates[i].x,
-                    trajec.coordinates[i].y,
-                    trajec.speed[i],
-                    trajec.bearing[i],
-                    trajec.stoped[i],
-                    trajec.trips[i],
-                    trajec.timestamps[i],
-                    trajec.pois[i]
-                )
-            }
+        println!("oid,lon,lat,speed,bearing,stoped,trip,timestamp,poi_id,gps");
+        for trajec in self.object.values() {
+            trajec.to_csv();
         }
     }
 
-    pub fn concat(&self, trajcol: TrajCollection) {
+    pub fn concat(&self, _trajcol: TrajCollection) {
         todo!()
     }
 
@@ -414,114 +624,84 @@ impl TrajCollection {
         bearing: f32,
         timestamp: i32,
         my_oid: i32,
+        cfg: &PipelineConfig,
     ) -> Vec<i32> {
         let mut flocked_oids = vec![];
-        if speed > crate::STOP_SPEED_THR {
-            for (oid, traj) in self.object.iter() {
-                if oid.to_owned() == my_oid || traj.stoped.last().unwrap().to_owned() == 1 {
-                    continue;
+        if speed > cfg.stop_speed_thr {
+            let mut check = |oid: i32, head: &Head| {
+                if oid == my_oid || head.stoped == 1 {
+                    return;
                 };
-                let dt = timestamp - traj.timestamps.last().unwrap();
-                let db = (bearing - traj.bearing.last().unwrap()).abs();
-                if dt > crate::FLOCKS_MAX_DT_THRESHOLD || db > crate::FLOCKS_MAX_BEARING_THRESHOLD {
-                    continue;
+                let dt = timestamp - head.t;
+                let db = (bearing - head.bearing).abs();
+                if dt > cfg.flocks_max_dt_threshold || db > cfg.flocks_max_bearing_threshold {
+                    return;
                 }
                 // todo fix this
-                let extrapolated = traj.extrapolate_next(dt);
-                if coord.haversine(&extrapolated) < crate::FLOCKS_DISTANCE_THRESHOLD {
-                    flocked_oids.push(oid.to_owned())
+                let extrapolated = head.extrapolate(dt);
+                if coord.haversine(&extrapolated) < cfg.flocks_distance_threshold {
+                    flocked_oids.push(oid)
+                }
+            };
+
+            match &self.flock_index {
+                Some(index) => {
+                    for (oid, head) in index.read().unwrap().iter() {
+                        check(*oid, head);
+                    }
+                }
+                None => {
+                    for (oid, traj) in self.object.iter() {
+                        if let Some(head) = Head::of(traj) {
+                            check(*oid, &head);
+                        }
+                    }
                 }
             }
         }
+        // the objects are visited in hash order
+        flocked_oids.sort_unstable();
         flocked_oids
     }
 
-    pub fn predict_for_oid(&self, oid: i32, model: &CModule) -> Option<Coordinate> {
-        // eprintln!();
-
-        let traj = self.object.get(&oid)?;
-
-        if traj.coordinates.len() < 13 {
-            return None;
-        }
-
-        let mut xs = vec![];
-        let mut ys = vec![];
-        let mut ts = vec![];
-
-        let ft_to_m = Proj::new_known_crs("EPSG:4326", "EPSG:3857", None).unwrap();
-
-        let mut data = vec![];
-
-        for coord in traj.coordinates[traj.coordinates.len() - 13..].iter() {
-            let projected: (f32, f32) = ft_to_m.convert((coord.x, coord.y)).unwrap();
-            xs.push(projected.0);
-            ys.push(projected.1);
-        }
-
-        for timestamp in traj.timestamps[traj.timestamps.len() - 13..].iter() {
-            ts.push(timestamp.clone());
-        }
-
-        let tmp = ts
-            .iter()
-            .zip(ts.iter().skip(1))
-            .map(|(tsa, tsb)| (tsb - tsa) as f32 / 1800.0)
-            .collect::<Vec<_>>();
-
-        for (a, b, c, d) in izip!(
-            tmp[1..11].iter(),
-            tmp[2..12].iter(),
-            xs.iter().skip(2).zip(xs.iter().skip(3)),
-            ys.iter().skip(2).zip(ys.iter().skip(3))
-        ) {
-            data.push(a.to_owned() as f32);
-            data.push(b.to_owned() as f32);
-            data.push((c.1 - c.0 - 0.604) / 245.366);
-            data.push((d.1 - d.0 - 1.619) / 232.757);
+    // The points predicted for `oid` up to `horizons` seconds ahead, see predictor::rollout
+    pub fn predict_for_oid(
+        &self,
+        oid: i32,
+        predictor: &dyn Predictor,
+        horizons: &[i32],
+        step: i32,
+    ) -> Result<Trajectory, MarshalError> {
+        match self.object.get(&oid) {
+            Some(traj) => {
+                Ok(
+                    predictor::rollout(predictor, vec![traj.tail(PREDICT_WINDOW)], horizons, step)?
+                        .remove(0),
+                )
+            }
+            None => Ok(Trajectory::new_empty(oid, usize::MAX)),
         }
-        // let mut binding = data.as_mut_slice().chunks_mut(11).collect::<Vec<_>>().as_slice();
-        // let data = binding.as_slice();
-
-        let output = Vec::<f32>::from(
-            model
-                .forward_ts(&[
-                    Tensor::of_slice(data.as_slice()).reshape(&[1, 10, 4]),
-                    Tensor::of_slice(&[1]),
-                ])
-                .unwrap(),
-        );
-
-        let (predlondiff, predlatdiff) = (output[0] * 245.366 + 0.604, output[1] * 232.757 + 1.619);
-
-        let predlon = xs.last().unwrap() + predlondiff;
-        let predlat = ys.last().unwrap() + predlatdiff;
-
-        let m_to_deg = Proj::new_known_crs("EPSG:3857", "EPSG:4326", None).unwrap();
-
-        Some(Coordinate::from_tuple(
-            m_to_deg.convert((predlon, predlat)).unwrap(),
-        ))
     }
 }
 
+#[derive(Debug, Clone)]
 pub struct Pois {
     pub pois: Vec<Coordinate>,
 }
 
 impl Pois {
-    pub fn new_from_path(path: &str) -> Pois {
-        let mut reader_pois = csv::Reader::from_path(path).unwrap();
+    pub fn new_from_path(path: &str) -> Result<Pois, MarshalError> {
+        let mut reader_pois = csv::Reader::from_path(path)?;
 
         let mut pois_list = vec![];
 
         for record in reader_pois.deserialize() {
             // for record in tqdm!(reader.deserialize()) {
-            let record: Coordinate = record.unwrap();
+            let record: Coordinate = record?;
             // records.push(record);
             pois_list.push(record);
         }
-        Pois { pois: pois_list }
+        Ok(Pois { pois: pois_list })
     }
 
     pub fn pretty(&self) {
@@ -531,18 +711,16 @@ impl Pois {
     }
 
     pub fn nearest(&self, coord: &Coordinate, threshold: f32) -> i32 {
-        let (poi_id, distance) = self
+        // an empty POI list never matches
+        match self
             .pois
             .iter()
-            .map(|pnt| pnt.haversine(&coord))
+            .map(|pnt| pnt.haversine(coord))
             .enumerate()
             .min_by(|(_, a), (_, b)| a.total_cmp(b))
-            .map(|(index, dist)| (index, dist))
-            .unwrap();
-        if distance < threshold {
-            poi_id as i32
-        } else {
-            -1
+        {
+            Some((poi_id, distance)) if distance < threshold => poi_id as i32,
+            _ => -1,
         }
     }
 }
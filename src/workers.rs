@@ -0,0 +1,104 @@
+use crate::dataflow::Stage;
+use crate::error::MarshalError;
+use crate::pipeline::Pipeline;
+use crate::stats::QueueStats;
+use crate::structs::{Record, Trajectory};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Iter, Receiver, RecvTimeoutError, SyncSender, TryIter, TrySendError};
+use std::sync::Arc;
+use std::time::Instant;
+
+// What the workers send back: the points stages emitted for a record, and the trajectories
+// they evicted meanwhile
+pub type Outputs = (Vec<(Stage, Trajectory)>, Vec<(Stage, Trajectory)>);
+
+// Errors carry the index of their record (in push order) when several workers can fail the
+// same record, which is skipped once all the same
+pub type WorkerOutput = Result<Outputs, (Option<usize>, MarshalError)>;
+
+// A pipeline spread over worker threads (see ShardedPipeline and StagedPipeline). The
+// outputs of a record come back once the workers are done with it
+pub trait Workers {
+    // Blocks while the queue the record goes to is full
+    fn push(&mut self, record: Record, due: Option<Instant>);
+
+    // Outputs that are ready, without waiting
+    fn ready(&self) -> TryIter<'_, WorkerOutput>;
+
+    // Stops the input and waits for the outputs of the records still queued
+    fn close(&mut self) -> Iter<'_, WorkerOutput>;
+
+    // Waits for the workers and merges their pipelines into one, with the queue stats
+    fn join(self: Box<Self>) -> Pipeline;
+}
+
+// The sending end of a bounded queue between two threads, which keeps track of how full
+// the queue gets
+pub struct Link<T> {
+    sender: SyncSender<T>,
+    depth: Arc<AtomicUsize>,
+    pub stats: QueueStats,
+}
+
+// The receiving end
+pub struct Inbox<T> {
+    receiver: Receiver<T>,
+    depth: Arc<AtomicUsize>,
+}
+
+pub fn queue<T>(from: &str, to: &str, capacity: usize) -> (Link<T>, Inbox<T>) {
+    let (sender, receiver) = mpsc::sync_channel(capacity);
+    let depth = Arc::new(AtomicUsize::new(0));
+    (
+        Link {
+            sender,
+            depth: depth.clone(),
+            stats: QueueStats::new(from, to, capacity),
+        },
+        Inbox { receiver, depth },
+    )
+}
+
+impl<T> Link<T> {
+    // Waits while the queue is full, false once the receiver is gone
+    pub fn send(&mut self, message: T) -> bool {
+        // counted before sending, so the receiver never takes a message that is not counted
+        self.stats
+            .observe(self.depth.fetch_add(1, Ordering::Relaxed));
+        match self.sender.try_send(message) {
+            Ok(()) => true,
+            Err(TrySendError::Full(message)) => {
+                let waiting = Instant::now();
+                let sent = self.sender.send(message).is_ok();
+                self.stats.blocked(waiting.elapsed());
+                sent
+            }
+            Err(TrySendError::Disconnected(_)) => false,
+        }
+    }
+}
+
+impl<T> Inbox<T> {
+    // Waits for a message until `deadline`, or for as long as it takes without one
+    pub fn recv_until(&mut self, deadline: Option<Instant>) -> Result<T, RecvTimeoutError> {
+        let message = match deadline {
+            Some(deadline) => self
+                .receiver
+                .recv_timeout(deadline.saturating_duration_since(Instant::now()))?,
+            None => self
+                .receiver
+                .recv()
+                .map_err(|_| RecvTimeoutError::Disconnected)?,
+        };
+        self.depth.fetch_sub(1, Ordering::Relaxed);
+        Ok(message)
+    }
+}
+
+impl<T> Iterator for Inbox<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.recv_until(None).ok()
+    }
+}
@@ -0,0 +1,157 @@
+use marshal::checkpoint::{self, Checkpoint};
+use marshal::reorder::{LatePolicy, Reordered};
+use marshal::sinks::{Geometry, SinkSpec};
+use marshal::sources::{open_source, ColumnMapping};
+use marshal::{Dataflow, Pipeline, PipelineConfig, Pois, Sinks};
+use std::borrow::Cow;
+
+static INPUT: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/brest.csv");
+static POIS: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/ports_brest.csv");
+
+fn dataflow() -> Dataflow {
+    Dataflow::new(vec![
+        "clean".parse().unwrap(),
+        "resample".parse().unwrap(),
+        "compress".parse().unwrap(),
+    ])
+    .unwrap()
+}
+
+fn config() -> PipelineConfig {
+    // exercise the reorder buffer and the idle eviction, both are part of the checkpoint
+    PipelineConfig {
+        allowed_lateness: 30,
+        on_late: LatePolicy::Drop,
+        idle_timeout: 3600,
+        ..PipelineConfig::default()
+    }
+}
+
+fn open(resumed: Option<Checkpoint>) -> (Reordered, Pipeline) {
+    let cfg = config();
+    let mut source = open_source(INPUT, &ColumnMapping::default()).unwrap();
+    if let Some(resumed) = &resumed {
+        checkpoint::skip(&mut source, resumed.offset).unwrap();
+    }
+    let mut reordered =
+        Reordered::new(INPUT, source, cfg.allowed_lateness, cfg.on_late, None).unwrap();
+    let pois = Pois::new_from_path(POIS).unwrap();
+    let mut pipeline = Pipeline::new(dataflow(), cfg, pois).unwrap();
+    pipeline.keep_evicted = true;
+
+    if let Some(resumed) = resumed {
+        reordered.resume(resumed.offset, resumed.reorder.into_owned());
+        pipeline.restore(resumed.pipeline).unwrap();
+    }
+    (reordered, pipeline)
+}
+
+// Everything the stages emitted and evicted for the next `limit` records
+fn process(reordered: &mut Reordered, pipeline: &mut Pipeline, limit: usize) -> Vec<String> {
+    let mut outputs = vec![];
+    for record in reordered.take(limit) {
+        let emitted = pipeline.push(record.unwrap()).unwrap();
+        for (stage, traj) in emitted.iter().chain(pipeline.take_evicted().iter()) {
+            outputs.push(format!("{} {:?}", stage, traj));
+        }
+    }
+    outputs
+}
+
+#[test]
+fn resumed_run_matches_uninterrupted_run() {
+    let (mut reordered, mut pipeline) = open(None);
+    let uninterrupted = process(&mut reordered, &mut pipeline, usize::MAX);
+    assert!(!uninterrupted.is_empty());
+
+    let path = std::env::temp_dir().join(format!("marshal-checkpoint-{}.bin", std::process::id()));
+    let path = path.to_str().unwrap();
+
+    let (mut reordered, mut pipeline) = open(None);
+    let mut resumed = process(&mut reordered, &mut pipeline, 4000);
+    Checkpoint {
+        input: INPUT.to_string(),
+        dataflow: dataflow().to_string(),
+        offset: reordered.consumed(),
+        reorder: Cow::Borrowed(reordered.buffer()),
+        pipeline: pipeline.state(),
+        sinks: vec![],
+    }
+    .save(path)
+    .unwrap();
+    drop((reordered, pipeline));
+
+    let loaded = Checkpoint::load(path).unwrap();
+    loaded.check(INPUT, &dataflow()).unwrap();
+    let (mut reordered, mut pipeline) = open(Some(loaded));
+    resumed.extend(process(&mut reordered, &mut pipeline, usize::MAX));
+    std::fs::remove_file(path).unwrap();
+
+    assert_eq!(resumed.len(), uninterrupted.len());
+    assert_eq!(resumed, uninterrupted);
+}
+
+// Writes what the stages emit for the next `limit` records
+fn write(reordered: &mut Reordered, pipeline: &mut Pipeline, sinks: &mut Sinks, limit: usize) {
+    for record in reordered.take(limit) {
+        let emitted = pipeline.push(record.unwrap()).unwrap();
+        sinks.write(&emitted).unwrap();
+    }
+}
+
+#[test]
+fn resumed_run_continues_the_sinks() {
+    let dir = std::env::temp_dir().join(format!("marshal-sinks-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let specs = |run: &str| -> Vec<SinkSpec> {
+        ["clean.csv", "compress.jsonl"]
+            .iter()
+            .map(|file| {
+                let stage = file.split('.').next().unwrap();
+                let path = dir.join(format!("{}-{}", run, file));
+                format!("{}={}", stage, path.display()).parse().unwrap()
+            })
+            .collect()
+    };
+    let path = dir.join("checkpoint.bin");
+    let path = path.to_str().unwrap();
+
+    let (mut reordered, mut pipeline) = open(None);
+    let mut sinks = Sinks::open(&specs("uninterrupted"), Geometry::Points).unwrap();
+    write(&mut reordered, &mut pipeline, &mut sinks, usize::MAX);
+    sinks.finish().unwrap();
+
+    let (mut reordered, mut pipeline) = open(None);
+    let mut sinks = Sinks::open(&specs("resumed"), Geometry::Points).unwrap();
+    write(&mut reordered, &mut pipeline, &mut sinks, 4000);
+    Checkpoint {
+        input: INPUT.to_string(),
+        dataflow: dataflow().to_string(),
+        offset: reordered.consumed(),
+        reorder: Cow::Borrowed(reordered.buffer()),
+        pipeline: pipeline.state(),
+        sinks: sinks.checkpoint().unwrap(),
+    }
+    .save(path)
+    .unwrap();
+    // the points written after the checkpoint are written again after resuming
+    write(&mut reordered, &mut pipeline, &mut sinks, 1000);
+    drop((reordered, pipeline, sinks));
+
+    let loaded = Checkpoint::load(path).unwrap();
+    let mut sinks = Sinks::resume(&specs("resumed"), &loaded.sinks).unwrap();
+    let (mut reordered, mut pipeline) = open(Some(loaded));
+    write(&mut reordered, &mut pipeline, &mut sinks, usize::MAX);
+    sinks.finish().unwrap();
+
+    for (uninterrupted, resumed) in specs("uninterrupted").iter().zip(specs("resumed")) {
+        let uninterrupted = std::fs::read_to_string(&uninterrupted.path).unwrap();
+        assert!(uninterrupted.lines().count() > 1, "{}", resumed.path);
+        assert!(
+            uninterrupted == std::fs::read_to_string(&resumed.path).unwrap(),
+            "{} differs from the uninterrupted run",
+            resumed.path
+        );
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,118 @@
+use arrow::array::{Array, Float32Array, Int32Array, ListArray, StringArray, UInt8Array};
+use arrow::ipc::reader::FileReader;
+use arrow::record_batch::RecordBatch;
+use marshal::columnar::{schema, ArrowIpcSink, ParquetSink};
+use marshal::sinks::Sink;
+use marshal::structs::Reported;
+use marshal::{Coordinate, Stage, Trajectory};
+use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+use std::fs::File;
+use std::path::PathBuf;
+
+// Three points of object 1, the first one flocked with objects 7 and 9 and reporting its
+// sog and ship type
+fn trajectory() -> Trajectory {
+    let mut traj = Trajectory::new_empty(1, usize::MAX);
+    for i in 0..3 {
+        let (gps, reported) = match i {
+            0 => (
+                vec![7, 9],
+                Reported {
+                    sog: Some(10.5),
+                    ship_type: Some(70),
+                    ..Reported::default()
+                },
+            ),
+            _ => (vec![], Reported::default()),
+        };
+        traj.insert_unbounded(
+            Coordinate {
+                x: -4.5 + i as f32 / 100.0,
+                y: 48.3,
+            },
+            1000 + 60 * i,
+            10.0,
+            90.0,
+            -1,
+            0,
+            0,
+            gps,
+            reported,
+        );
+    }
+    traj
+}
+
+fn path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("marshal-{}-{}", std::process::id(), name))
+}
+
+// Batches of two rows, so the three points of each write span a batch boundary
+fn write(mut sink: impl Sink) {
+    sink.write(Stage::Clean, &trajectory()).unwrap();
+    sink.write(Stage::Compress, &trajectory()).unwrap();
+    sink.finish().unwrap();
+}
+
+fn column<'a, T: 'static>(batch: &'a RecordBatch, name: &str) -> &'a T {
+    batch
+        .column_by_name(name)
+        .unwrap()
+        .as_any()
+        .downcast_ref::<T>()
+        .unwrap()
+}
+
+fn assert_written(batches: Vec<RecordBatch>) {
+    for batch in &batches {
+        assert_eq!(batch.schema(), schema());
+    }
+    let batch = arrow::compute::concat_batches(&schema(), &batches).unwrap();
+    assert_eq!(batch.num_rows(), 6);
+
+    let stage = column::<StringArray>(&batch, "stage");
+    assert_eq!((stage.value(0), stage.value(3)), ("clean", "compress"));
+    assert_eq!(column::<Int32Array>(&batch, "oid").value(5), 1);
+    assert_eq!(column::<Float32Array>(&batch, "lon").value(1), -4.49);
+    assert_eq!(column::<Int32Array>(&batch, "timestamp").value(2), 1120);
+
+    let gps = column::<ListArray>(&batch, "gps");
+    let flock = gps.value(0);
+    let flock = flock.as_any().downcast_ref::<Int32Array>().unwrap();
+    assert_eq!(flock.values().to_vec(), vec![7, 9]);
+    // a point outside a flock has an empty list, not a null one
+    assert!(gps.is_valid(1));
+    assert_eq!(gps.value(1).len(), 0);
+
+    // the reported fields are null where the input did not carry them
+    let sog = column::<Float32Array>(&batch, "sog");
+    assert_eq!(sog.value(0), 10.5);
+    assert!(sog.is_null(1));
+    assert_eq!(column::<UInt8Array>(&batch, "ship_type").value(3), 70);
+    assert!(column::<Float32Array>(&batch, "cog").is_null(0));
+}
+
+#[test]
+fn parquet_sink_reads_back_with_the_schema() {
+    let path = path("points.parquet");
+    write(ParquetSink::create(path.to_str().unwrap(), 2).unwrap());
+    let batches = ParquetRecordBatchReaderBuilder::try_new(File::open(&path).unwrap())
+        .unwrap()
+        .build()
+        .unwrap()
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_written(batches);
+}
+
+#[test]
+fn arrow_ipc_sink_reads_back_with_the_schema() {
+    let path = path("points.arrow");
+    write(ArrowIpcSink::create(path.to_str().unwrap(), 2).unwrap());
+    let reader = FileReader::try_new(File::open(&path).unwrap(), None).unwrap();
+    assert_eq!(reader.schema(), schema());
+    let batches = reader.collect::<Result<Vec<_>, _>>().unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_written(batches);
+}
@@ -0,0 +1,56 @@
+use marshal::{MarshalError, PipelineConfig};
+
+static CONFIG: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/marshal.toml");
+
+// A default config with `change` made to it fails validation, naming the value
+fn rejected(change: impl FnOnce(&mut PipelineConfig), name: &str) {
+    let mut cfg = PipelineConfig::default();
+    change(&mut cfg);
+    match cfg.validate() {
+        Err(MarshalError::Config(message)) => assert!(message.starts_with(name), "{}", message),
+        Err(e) => panic!("{}: {}", name, e),
+        Ok(()) => panic!("{} accepted", name),
+    }
+}
+
+#[test]
+fn rejects_values_the_stages_cannot_work_with() {
+    assert!(PipelineConfig::default().validate().is_ok());
+
+    rejected(|cfg| cfg.rate = 0, "rate");
+    rejected(|cfg| cfg.history_size = 0, "history_size");
+    rejected(|cfg| cfg.history_window = -1, "history_window");
+    rejected(|cfg| cfg.idle_timeout = -1, "idle_timeout");
+    rejected(|cfg| cfg.allowed_lateness = -1, "allowed_lateness");
+    rejected(|cfg| cfg.max_speed = -1.0, "max_speed");
+    rejected(|cfg| cfg.opw_epsilon = f32::NAN, "opw_epsilon");
+    rejected(|cfg| cfg.predict_batch_size = 0, "predict_batch_size");
+    for wait in [-1.0, f64::NAN, f64::INFINITY] {
+        rejected(|cfg| cfg.predict_batch_wait = wait, "predict_batch_wait");
+    }
+    rejected(|cfg| cfg.predict_step = -1, "predict_step");
+    rejected(
+        |cfg| cfg.predict_horizons = vec![300, -1],
+        "predict_horizons",
+    );
+}
+
+// the only test reading the environment, the others would race with it
+#[test]
+fn environment_overrides_the_config_file() {
+    std::env::set_var("MARSHAL_MAX_SPEED", "40");
+    std::env::set_var("MARSHAL_PREDICT_HORIZONS", "300, 600,1800");
+    let cfg = PipelineConfig::load(Some(CONFIG)).unwrap();
+    assert_eq!(cfg.max_speed, 40.0);
+    assert_eq!(cfg.predict_horizons, vec![300, 600, 1800]);
+    // and the rest comes from the file
+    assert_eq!(cfg.rate, 10);
+
+    // overrides are validated like the file
+    std::env::set_var("MARSHAL_MAX_SPEED", "-40");
+    assert!(PipelineConfig::load(Some(CONFIG)).is_err());
+    std::env::set_var("MARSHAL_MAX_SPEED", "fast");
+    assert!(PipelineConfig::load(Some(CONFIG)).is_err());
+    std::env::remove_var("MARSHAL_MAX_SPEED");
+    std::env::remove_var("MARSHAL_PREDICT_HORIZONS");
+}
@@ -0,0 +1,204 @@
+use marshal::kinematic::{Ctrv, DeadReckoning, Regression};
+use marshal::predictor::{self, rollout, PredictorKind};
+use marshal::structs::Reported;
+use marshal::{Coordinate, PipelineConfig, Predictor, Trajectory};
+
+static SPEED: f32 = 10.0; // knots
+static STEP: i32 = 60; // seconds between points
+static POINTS: usize = 15;
+
+// Points every STEP seconds at SPEED, with the bearing of every point
+fn track(points: Vec<(Coordinate, f32)>) -> Trajectory {
+    let mut traj = Trajectory::new_empty(1, usize::MAX);
+    for (i, (coord, bearing)) in points.into_iter().enumerate() {
+        traj.insert_unbounded(
+            coord,
+            i as i32 * STEP,
+            SPEED,
+            bearing,
+            -1,
+            0,
+            0,
+            vec![],
+            Reported::default(),
+        );
+    }
+    traj
+}
+
+// `east` and `north` metres from `origin`, on the plane tangent to it
+fn offset(origin: (f64, f64), east: f64, north: f64) -> Coordinate {
+    let metres_per_degree = 111_320.0;
+    let lon = origin.0 + east / (metres_per_degree * origin.1.to_radians().cos());
+    Coordinate {
+        x: ((lon + 540.0) % 360.0 - 180.0) as f32,
+        y: (origin.1 + north / metres_per_degree) as f32,
+    }
+}
+
+// POINTS points and the one after them, in a straight line
+fn straight(origin: (f64, f64), bearing: f32) -> (Vec<(Coordinate, f32)>, Coordinate) {
+    let step = SPEED as f64 * 1852.0 / 3600.0 * STEP as f64;
+    let (east, north) = (
+        (bearing as f64).to_radians().sin(),
+        (bearing as f64).to_radians().cos(),
+    );
+    let at = |i: usize| offset(origin, east * step * i as f64, north * step * i as f64);
+    ((0..POINTS).map(|i| (at(i), bearing)).collect(), at(POINTS))
+}
+
+fn predict(predictor: &dyn Predictor, traj: &Trajectory) -> Coordinate {
+    predictor.predict(traj).unwrap().unwrap()
+}
+
+#[test]
+fn all_agree_on_a_straight_track() {
+    let (points, expected) = straight((-4.5, 48.3), 45.0);
+    let traj = track(points);
+
+    for predictor in [
+        &DeadReckoning as &dyn Predictor,
+        &Ctrv as &dyn Predictor,
+        &Regression as &dyn Predictor,
+    ] {
+        // nautical miles, i.e. within 2 metres of a 300 metre step
+        let distance = predict(predictor, &traj).haversine(&expected);
+        assert!(distance < 0.001, "{} nm off", distance);
+    }
+}
+
+#[test]
+fn ctrv_follows_a_constant_rate_turn() {
+    // turning right 10 degrees a step on a circle around `center`
+    let center = (-4.5, 48.3);
+    let rate = 10f64.to_radians() / STEP as f64;
+    let radius = SPEED as f64 * 1852.0 / 3600.0 / rate;
+    let at = |step: usize| {
+        let heading = rate * (step as i32 * STEP) as f64;
+        let coord = offset(center, -radius * heading.cos(), radius * heading.sin());
+        (coord, heading.to_degrees() as f32)
+    };
+
+    let traj = track((0..POINTS).map(at).collect());
+    let (expected, _) = at(POINTS);
+
+    let ctrv = predict(&Ctrv, &traj).haversine(&expected);
+    let dead_reckoning = predict(&DeadReckoning, &traj).haversine(&expected);
+    assert!(ctrv < 0.002, "ctrv {} nm off", ctrv);
+    // the tangent misses the arc by some 27 metres
+    assert!(
+        dead_reckoning > 0.01,
+        "dead reckoning {} nm off",
+        dead_reckoning
+    );
+}
+
+#[test]
+fn regression_unwraps_the_antimeridian() {
+    let (points, expected) = straight((179.98, 0.0), 90.0);
+    let traj = track(points);
+    // the last points are past it
+    assert!(traj.coordinates.first().unwrap().x > 0.0);
+    assert!(traj.coordinates.last().unwrap().x < 0.0);
+
+    let predicted = predict(&Regression, &traj);
+    assert!(predicted.x < -179.0, "{:?}", predicted);
+    let distance = predicted.haversine(&expected);
+    assert!(distance < 0.001, "{} nm off", distance);
+}
+
+#[test]
+fn dead_reckoning_rolls_out_to_the_horizon() {
+    let (points, _) = straight((-4.5, 48.3), 45.0);
+    let traj = track(points);
+    let (last, from) = (
+        *traj.timestamps.last().unwrap(),
+        traj.coordinates.last().unwrap().clone(),
+    );
+    let rolled = |horizons: &[i32]| {
+        rollout(&DeadReckoning, vec![traj.clone()], horizons, STEP)
+            .unwrap()
+            .remove(0)
+    };
+
+    // a step at a time, the last one cut short to stop at the horizon
+    let predicted = rolled(&[5 * STEP + 30]);
+    let mut expected: Vec<i32> = (1..=5).map(|k| last + k * STEP).collect();
+    expected.push(last + 5 * STEP + 30);
+    assert_eq!(predicted.timestamps, expected);
+    // nautical miles covered at SPEED, within 1% as every step derives the speed again in f32
+    let distance = from.haversine(predicted.coordinates.last().unwrap());
+    let covered = SPEED * (5 * STEP + 30) as f32 / 3600.0;
+    assert!(
+        (distance - covered).abs() < covered * 0.01,
+        "{} nm covered, not {}",
+        distance,
+        covered
+    );
+
+    // and at every horizon on the way
+    let predicted = rolled(&[STEP + 30, 3 * STEP]);
+    assert_eq!(
+        predicted.timestamps,
+        vec![
+            last + STEP,
+            last + STEP + 30,
+            last + 2 * STEP,
+            last + 3 * STEP
+        ]
+    );
+
+    // 0 predicts a single step
+    assert_eq!(rolled(&[0]).timestamps, vec![last + STEP]);
+}
+
+#[test]
+fn default_config_keeps_the_predicted_next_point() {
+    let cfg = PipelineConfig {
+        predictor: PredictorKind::Onnx,
+        onnx_model_path: concat!(env!("CARGO_MANIFEST_DIR"), "/model.onnx").to_string(),
+        ..PipelineConfig::default()
+    };
+    let onnx = predictor::load(&cfg).unwrap();
+
+    // sampled irregularly, none of the intervals is cfg.rate
+    let (points, _) = straight((-4.5, 48.3), 45.0);
+    let mut traj = Trajectory::new_empty(1, usize::MAX);
+    let mut t = 0;
+    for (i, (coord, bearing)) in points.into_iter().enumerate() {
+        t += [60, 45, 90, 75][i % 4];
+        traj.insert_unbounded(
+            coord,
+            t,
+            SPEED,
+            bearing,
+            -1,
+            0,
+            0,
+            vec![],
+            Reported::default(),
+        );
+    }
+    let n = traj.timestamps.len();
+    let next = 2 * traj.timestamps[n - 1] - traj.timestamps[n - 2];
+
+    for predictor in [
+        &DeadReckoning as &dyn Predictor,
+        &Ctrv as &dyn Predictor,
+        &Regression as &dyn Predictor,
+        onnx.as_ref(),
+    ] {
+        let expected = predict(predictor, &traj);
+        let predicted = rollout(
+            predictor,
+            vec![traj.clone()],
+            &cfg.predict_horizons,
+            cfg.predict_interval(),
+        )
+        .unwrap()
+        .remove(0);
+        assert_eq!(predicted.timestamps, vec![next]);
+        let coord = &predicted.coordinates[0];
+        assert_eq!((coord.x, coord.y), (expected.x, expected.y));
+    }
+}
@@ -0,0 +1,83 @@
+use marshal::network::NetworkSource;
+use marshal::sources::ColumnMapping;
+use marshal::{MarshalError, Record};
+use std::io::Write;
+use std::net::{TcpStream, UdpSocket};
+
+// the idle timeout only ends a test that would otherwise hang
+fn bind(scheme: &str) -> NetworkSource {
+    let url = format!("{}://127.0.0.1:0?idle=5", scheme);
+    NetworkSource::bind(&url, &ColumnMapping::default()).unwrap()
+}
+
+fn received(source: &mut NetworkSource, n: usize) -> Vec<Record> {
+    let records: Vec<Record> = source.take(n).map(|record| record.unwrap()).collect();
+    assert_eq!(records.len(), n);
+    records
+}
+
+fn positions(records: &[Record]) -> Vec<(i32, i32, f32, f32)> {
+    records
+        .iter()
+        .map(|record| (record.oid, record.t, record.lon, record.lat))
+        .collect()
+}
+
+#[test]
+fn receives_csv_lines_over_tcp() {
+    let mut source = bind("tcp");
+    let mut peer = TcpStream::connect(source.local_addr()).unwrap();
+    // the header sets the column order of the lines after it, the last line has no newline
+    peer.write_all(b"1,100,-4.5,48.3\nt,oid,lat,lon\n101,2,48.4,-4.6\n102,2,48.5,-4.7")
+        .unwrap();
+    drop(peer);
+
+    let records = received(&mut source, 3);
+    assert_eq!(
+        positions(&records),
+        vec![
+            (1, 100, -4.5, 48.3),
+            (2, 101, -4.6, 48.4),
+            (2, 102, -4.7, 48.5)
+        ]
+    );
+
+    source.shutdown();
+    assert!(source.next().is_none());
+}
+
+#[test]
+fn receives_csv_lines_and_nmea_over_udp() {
+    let mut source = bind("udp");
+    let peer = UdpSocket::bind("127.0.0.1:0").unwrap();
+    // a datagram may hold several lines
+    peer.send_to(b"1,100,-4.5,48.3\n1,110,-4.6,48.4\n", source.local_addr())
+        .unwrap();
+    peer.send_to(
+        b"1443650401 !AIVDM,1,1,,A,13HOI:0P0000VOHLCnHQKwvL05Ip,0*23",
+        source.local_addr(),
+    )
+    .unwrap();
+
+    let records = received(&mut source, 3);
+    assert_eq!(
+        positions(&records[..2]),
+        vec![(1, 100, -4.5, 48.3), (1, 110, -4.6, 48.4)]
+    );
+    assert_eq!((records[2].oid, records[2].t), (227006760, 1443650401));
+
+    source.shutdown();
+    assert!(source.next().is_none());
+}
+
+#[test]
+fn rejects_idle_timeouts_that_are_no_durations() {
+    for idle in ["-1", "NaN", "inf", "soon"] {
+        let url = format!("tcp://127.0.0.1:0?idle={}", idle);
+        match NetworkSource::bind(&url, &ColumnMapping::default()) {
+            Err(MarshalError::Config(message)) => assert!(message.contains("idle"), "{}", message),
+            Err(e) => panic!("idle={}: {}", idle, e),
+            Ok(_) => panic!("idle={} accepted", idle),
+        }
+    }
+}
@@ -0,0 +1,159 @@
+use marshal::nmea::NmeaDecoder;
+use marshal::Record;
+
+// Published sentences, checked against an independent decode of the bit fields
+static TYPE_1: &str = "!AIVDM,1,1,,A,13HOI:0P0000VOHLCnHQKwvL05Ip,0*23";
+static TYPE_18: &str = "!AIVDM,1,1,,A,B52K>;h00Fc>jpUlNV@ikwpUoP06,0*4C";
+static TYPE_19: &str = "!AIVDM,1,1,,B,C5N3SRgPEnJGEBT>NhWAwwo862PaLELTBJ:V00000000S0D:R220,0*0B";
+static TYPE_27: &str = "!AIVDM,1,1,,B,KC5E2b@U19PFdLbMuc5=ROv62<7m,0*16";
+
+// `!AIVDM,...` with its checksum, for fragments made up by the tests
+fn sentence(body: &str) -> String {
+    let checksum = body.bytes().fold(0u8, |acc, b| acc ^ b);
+    format!("!{}*{:02X}", body, checksum)
+}
+
+fn decode(decoder: &mut NmeaDecoder, line: &str) -> Option<Record> {
+    decoder.decode(line).map(|record| record.unwrap())
+}
+
+fn assert_near(actual: f32, expected: f64) {
+    assert!(
+        (actual as f64 - expected).abs() < 1e-4,
+        "{} != {}",
+        actual,
+        expected
+    );
+}
+
+#[test]
+fn decodes_class_a_position_report() {
+    let record = decode(&mut NmeaDecoder::new(), &format!("1443650401 {}", TYPE_1)).unwrap();
+    assert_eq!(record.oid, 227006760);
+    assert_eq!(record.t, 1443650401);
+    assert_near(record.lon, 0.13138);
+    assert_near(record.lat, 49.475577);
+    assert_eq!(record.sog, Some(0.0));
+    assert_eq!(record.cog, Some(36.7));
+    // 511, not available
+    assert_eq!(record.heading, None);
+    assert_eq!(record.nav_status, Some(0));
+}
+
+#[test]
+fn decodes_class_b_position_report() {
+    let record = decode(&mut NmeaDecoder::new(), TYPE_18).unwrap();
+    assert_eq!(record.oid, 338087471);
+    assert_near(record.lon, -74.072132);
+    assert_near(record.lat, 40.68454);
+    assert_eq!(record.sog, Some(0.1));
+    assert_eq!(record.cog, Some(79.6));
+    assert_eq!(record.nav_status, None);
+}
+
+#[test]
+fn decodes_extended_class_b_report() {
+    let mut decoder = NmeaDecoder::new();
+    let record = decode(&mut decoder, TYPE_19).unwrap();
+    assert_eq!(record.oid, 367059850);
+    assert_near(record.lon, -88.810392);
+    assert_near(record.lat, 29.543695);
+    assert_eq!(record.sog, Some(8.7));
+    assert_eq!(record.cog, Some(335.9));
+    assert_eq!(record.ship_type, Some(70));
+}
+
+#[test]
+fn decodes_long_range_report() {
+    let record = decode(&mut NmeaDecoder::new(), TYPE_27).unwrap();
+    assert_eq!(record.oid, 206914217);
+    assert_near(record.lon, 137.023333);
+    assert_near(record.lat, 4.84);
+    assert_eq!(record.sog, Some(57.0));
+    assert_eq!(record.cog, Some(167.0));
+    assert_eq!(record.nav_status, Some(2));
+}
+
+#[test]
+fn reassembles_fragments() {
+    let mut decoder = NmeaDecoder::new();
+    let first = sentence("AIVDM,2,1,3,A,13HOI:0P0000VO,0");
+    let second = sentence("AIVDM,2,2,3,A,HLCnHQKwvL05Ip,0");
+
+    assert!(decoder.decode(&first).is_none());
+    let record = decode(&mut decoder, &second).unwrap();
+    assert_eq!(record.oid, 227006760);
+    assert_near(record.lon, 0.13138);
+    assert_near(record.lat, 49.475577);
+}
+
+#[test]
+fn drops_messages_with_fragments_out_of_order_or_missing() {
+    let mut decoder = NmeaDecoder::new();
+
+    // the second fragment before the first
+    assert!(decoder
+        .decode(&sentence("AIVDM,2,2,3,A,HLCnHQKwvL05Ip,0"))
+        .is_none());
+    assert!(decoder
+        .decode(&sentence("AIVDM,2,1,3,A,13HOI:0P0000VO,0"))
+        .is_none());
+
+    // the second of three never comes
+    assert!(decoder
+        .decode(&sentence("AIVDM,3,1,4,A,13HOI:0P0000VO,0"))
+        .is_none());
+    assert!(decoder
+        .decode(&sentence("AIVDM,3,3,4,A,HLCnHQKwvL05Ip,0"))
+        .is_none());
+
+    // nor does a late fragment complete it
+    assert!(decoder
+        .decode(&sentence("AIVDM,2,2,4,A,HLCnHQKwvL05Ip,0"))
+        .is_none());
+    assert_eq!(decode(&mut decoder, TYPE_1).unwrap().oid, 227006760);
+}
+
+#[test]
+fn rejects_checksum_mismatch() {
+    let corrupted = TYPE_1.replace("*23", "*24");
+    assert!(NmeaDecoder::new().decode(&corrupted).unwrap().is_err());
+
+    let missing = TYPE_1.replace("*23", "");
+    assert!(NmeaDecoder::new().decode(&missing).unwrap().is_err());
+}
+
+// Part B of a static data report (type 24) giving `mmsi` the ship type `ship_type`
+fn static_data(mmsi: u32, ship_type: u32) -> String {
+    let mut bits = [0u8; 168];
+    for (start, len, value) in [(0, 6, 24), (8, 30, mmsi), (38, 2, 1), (40, 8, ship_type)] {
+        for i in 0..len {
+            bits[start + i] = ((value >> (len - 1 - i)) & 1) as u8;
+        }
+    }
+    let payload: String = bits
+        .chunks(6)
+        .map(|chunk| {
+            let value = chunk.iter().fold(0, |acc, bit| acc << 1 | bit);
+            (if value < 40 { value + 48 } else { value + 56 }) as char
+        })
+        .collect();
+    sentence(&format!("AIVDM,1,1,,A,{},0", payload))
+}
+
+#[test]
+fn forgets_the_ship_types_of_vessels_not_heard_from_lately() {
+    let mut decoder = NmeaDecoder::with_capacity(2);
+    assert!(decoder.decode(&static_data(227006760, 70)).is_none());
+    assert_eq!(decode(&mut decoder, TYPE_1).unwrap().ship_type, Some(70));
+
+    // a position report keeps 227006760 more recent than 1, which is forgotten first
+    decoder.decode(&static_data(1, 30));
+    assert_eq!(decode(&mut decoder, TYPE_1).unwrap().ship_type, Some(70));
+    decoder.decode(&static_data(2, 30));
+    assert_eq!(decode(&mut decoder, TYPE_1).unwrap().ship_type, Some(70));
+
+    decoder.decode(&static_data(3, 30));
+    decoder.decode(&static_data(4, 30));
+    assert_eq!(decode(&mut decoder, TYPE_1).unwrap().ship_type, None);
+}
@@ -0,0 +1,90 @@
+#![cfg(feature = "torch")]
+
+use marshal::predictor::{self, PredictorKind};
+use marshal::sources::{open_source, ColumnMapping};
+use marshal::{Dataflow, Pipeline, PipelineConfig, Pois, Stage, Trajectory};
+
+static INPUT: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/brest.csv");
+static POIS: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/ports_brest.csv");
+
+fn config(predictor: PredictorKind) -> PipelineConfig {
+    PipelineConfig {
+        predictor,
+        model_path: concat!(env!("CARGO_MANIFEST_DIR"), "/vrf_brest_proto_jit_trace.pth")
+            .to_string(),
+        onnx_model_path: concat!(env!("CARGO_MANIFEST_DIR"), "/model.onnx").to_string(),
+        ..PipelineConfig::default()
+    }
+}
+
+#[test]
+fn onnx_predictions_match_torch() {
+    let torch = predictor::load(&config(PredictorKind::Torch)).unwrap();
+    let onnx = predictor::load(&config(PredictorKind::Onnx)).unwrap();
+
+    // the cleaned trajectories the predict stage reads by default
+    let dataflow = Dataflow::new(vec!["clean".parse().unwrap()]).unwrap();
+    let pois = Pois::new_from_path(POIS).unwrap();
+    let mut pipeline = Pipeline::new(dataflow, PipelineConfig::default(), pois).unwrap();
+
+    let mut compared = 0;
+    let mut sampled = vec![];
+    for (i, record) in open_source(INPUT, &ColumnMapping::default())
+        .unwrap()
+        .enumerate()
+    {
+        let record = record.unwrap();
+        let oid = record.oid;
+        pipeline.push(record).unwrap();
+        if i % 50 != 0 {
+            continue;
+        }
+
+        let traj = &pipeline.collections[&Stage::Clean].object[&oid];
+        sampled.push(traj.clone());
+        match (torch.predict(traj).unwrap(), onnx.predict(traj).unwrap()) {
+            (Some(expected), Some(predicted)) => {
+                // nautical miles, i.e. within 2 metres
+                let distance = expected.haversine(&predicted);
+                assert!(
+                    distance < 0.001,
+                    "oid {} at {}: torch {:?}, onnx {:?}",
+                    oid,
+                    traj.timestamps.last().unwrap(),
+                    expected,
+                    predicted
+                );
+                compared += 1;
+            }
+            (None, None) => {}
+            (expected, predicted) => panic!("torch {:?}, onnx {:?}", expected, predicted),
+        }
+    }
+    assert!(compared > 50, "only {} predictions compared", compared);
+
+    // a batch of the sampled windows, short ones included, predicts what they do one by one
+    let batch: Vec<&Trajectory> = sampled.iter().collect();
+    for (name, predictor) in [("torch", &torch), ("onnx", &onnx)] {
+        let predicted = predictor.predict_batch(&batch).unwrap();
+        assert_eq!(predicted.len(), batch.len());
+        for (traj, predicted) in batch.iter().zip(predicted) {
+            match (predictor.predict(traj).unwrap(), predicted) {
+                (Some(expected), Some(predicted)) => {
+                    let distance = expected.haversine(&predicted);
+                    assert!(
+                        distance < 0.001,
+                        "{} oid {}: alone {:?}, batched {:?}",
+                        name,
+                        traj.oid,
+                        expected,
+                        predicted
+                    );
+                }
+                (None, None) => {}
+                (expected, predicted) => {
+                    panic!("{} alone {:?}, batched {:?}", name, expected, predicted)
+                }
+            }
+        }
+    }
+}
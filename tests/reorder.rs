@@ -0,0 +1,123 @@
+use marshal::reorder::{LatePolicy, ReorderBuffer, Reordered};
+use marshal::{MarshalError, Record};
+use std::fs;
+
+fn record(oid: i32, t: i32) -> Record {
+    Record::new(oid, t, -4.5, 48.3)
+}
+
+fn key(record: &Record) -> (i32, i32) {
+    (record.oid, record.t)
+}
+
+#[test]
+fn releases_records_the_watermark_passed_in_event_time_order() {
+    let mut buffer = ReorderBuffer::new(10);
+    let mut released = vec![];
+    let mut push = |buffer: &mut ReorderBuffer, oid, t| {
+        let pushed = buffer.push(record(oid, t)).map_err(|late| key(&late));
+        while let Some(record) = buffer.pop_ready() {
+            released.push(key(&record));
+        }
+        pushed
+    };
+
+    assert!(push(&mut buffer, 1, 0).is_ok());
+    assert_eq!(buffer.watermark(), Some(-10));
+    assert!(push(&mut buffer, 1, 20).is_ok());
+    // within the allowed lateness of 20, released before it
+    assert!(push(&mut buffer, 1, 10).is_ok());
+    // behind the watermark, but no later point of oid 2 was released yet
+    assert!(push(&mut buffer, 2, 5).is_ok());
+    assert!(push(&mut buffer, 1, 30).is_ok());
+    assert_eq!(buffer.watermark(), Some(20));
+    // oid 1 already had 20 released
+    assert_eq!(push(&mut buffer, 1, 15), Err((1, 15)));
+    assert!(push(&mut buffer, 1, 25).is_ok());
+
+    assert_eq!(released, vec![(1, 0), (1, 10), (2, 5), (1, 20)]);
+    assert_eq!(buffer.len(), 2);
+
+    // the end of the input releases the rest
+    let rest: Vec<(i32, i32)> = std::iter::from_fn(|| buffer.pop())
+        .map(|record| key(&record))
+        .collect();
+    assert_eq!(rest, vec![(1, 25), (1, 30)]);
+}
+
+// Per-object sequences of 0, 10, .. 50 with every pair of points swapped, and one point of
+// oid 1 that comes after the watermark passed a later one
+fn shuffled() -> Vec<Result<Record, MarshalError>> {
+    [
+        (1, 10),
+        (2, 10),
+        (1, 0),
+        (2, 0),
+        (1, 30),
+        (2, 30),
+        (1, 20),
+        (2, 20),
+        (1, 50),
+        (2, 50),
+        (1, 5),
+        (1, 40),
+        (2, 40),
+    ]
+    .into_iter()
+    .map(|(oid, t)| Ok(record(oid, t)))
+    .collect()
+}
+
+fn reorder(policy: LatePolicy, side: Option<&str>) -> (Vec<(i32, i32)>, usize) {
+    let mut reordered =
+        Reordered::new("test", Box::new(shuffled().into_iter()), 15, policy, side).unwrap();
+    let released = reordered
+        .by_ref()
+        .map(|record| key(&record.unwrap()))
+        .collect();
+    (released, reordered.late())
+}
+
+#[test]
+fn reorders_a_shuffled_sequence_per_object() {
+    let expected = vec![
+        (1, 0),
+        (2, 0),
+        (1, 10),
+        (2, 10),
+        (1, 20),
+        (2, 20),
+        (1, 30),
+        (2, 30),
+        (1, 40),
+        (2, 40),
+        (1, 50),
+        (2, 50),
+    ];
+
+    for policy in [LatePolicy::Drop, LatePolicy::Count] {
+        let (released, late) = reorder(policy, None);
+        assert_eq!(released, expected);
+        assert_eq!(late, 1);
+    }
+
+    let path = std::env::temp_dir().join(format!("marshal-late-{}.csv", std::process::id()));
+    let (released, late) = reorder(LatePolicy::Side, path.to_str());
+    assert_eq!(released, expected);
+    assert_eq!(late, 1);
+
+    // only the late record goes to the side output
+    let mut side = csv::Reader::from_path(&path).unwrap();
+    let written: Vec<(i32, i32)> = side
+        .deserialize::<Record>()
+        .map(|record| key(&record.unwrap()))
+        .collect();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(written, vec![(1, 5)]);
+}
+
+#[test]
+fn side_policy_needs_an_output() {
+    let source = Box::new(shuffled().into_iter());
+    assert!(Reordered::new("test", source, 15, LatePolicy::Side, None).is_err());
+}
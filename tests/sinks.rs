@@ -0,0 +1,152 @@
+use marshal::sinks::{open_sink, Geometry};
+use marshal::structs::Reported;
+use marshal::{Coordinate, Stage, Trajectory};
+use serde_json::Value;
+use std::path::PathBuf;
+
+// `trips` points of object `oid`, one a minute, the first one reporting its sog and ship
+// type and flocked with object 9
+fn trajectory(oid: i32, trips: &[i32]) -> Trajectory {
+    let mut traj = Trajectory::new_empty(oid, usize::MAX);
+    for (i, trip) in trips.iter().enumerate() {
+        let reported = match i {
+            0 => Reported {
+                sog: Some(10.5),
+                ship_type: Some(70),
+                ..Reported::default()
+            },
+            _ => Reported::default(),
+        };
+        traj.insert_unbounded(
+            Coordinate {
+                x: -4.5 + i as f32 / 100.0,
+                y: 48.3,
+            },
+            1000 + 60 * i as i32,
+            10.0,
+            90.0,
+            -1,
+            *trip,
+            0,
+            if i == 0 { vec![9] } else { vec![] },
+            reported,
+        );
+    }
+    traj
+}
+
+fn path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("marshal-{}-{}", std::process::id(), name))
+}
+
+// Writes the trajectories to a sink at `name` and returns what it wrote
+fn written(name: &str, geometry: Geometry, trajs: &[Trajectory]) -> String {
+    let path = path(name);
+    let mut sink = open_sink(path.to_str().unwrap(), geometry).unwrap();
+    for traj in trajs {
+        sink.write(Stage::Clean, traj).unwrap();
+    }
+    sink.finish().unwrap();
+    let contents = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    contents
+}
+
+#[test]
+fn jsonl_writes_every_field_of_a_point() {
+    let contents = written("points.jsonl", Geometry::Points, &[trajectory(1, &[0, 0])]);
+    let points: Vec<Value> = contents
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(points.len(), 2);
+
+    let mut fields: Vec<&String> = points[0].as_object().unwrap().keys().collect();
+    fields.sort();
+    assert_eq!(
+        fields,
+        [
+            "bearing",
+            "cog",
+            "gps",
+            "heading",
+            "lat",
+            "lon",
+            "nav_status",
+            "oid",
+            "poi_id",
+            "ship_type",
+            "sog",
+            "speed",
+            "stage",
+            "stoped",
+            "timestamp",
+            "trip"
+        ]
+    );
+    assert_eq!(points[0]["stage"], "clean");
+    assert_eq!(points[0]["oid"], 1);
+    assert_eq!(points[0]["timestamp"], 1000);
+    assert_eq!(points[0]["gps"], serde_json::json!([9]));
+    assert_eq!(points[0]["sog"], 10.5);
+    assert_eq!(points[0]["ship_type"], 70);
+    // fields the input did not report are null
+    assert!(points[0]["cog"].is_null());
+    assert!(points[1]["sog"].is_null());
+}
+
+#[test]
+fn csv_writes_a_header_and_a_row_per_point() {
+    let contents = written("points.csv", Geometry::Points, &[trajectory(1, &[0, 0])]);
+    let mut reader = csv::Reader::from_reader(contents.as_bytes());
+    let headers = reader.headers().unwrap().clone();
+    let rows: Vec<csv::StringRecord> = reader.records().map(|row| row.unwrap()).collect();
+    assert_eq!(rows.len(), 2);
+
+    let field = |row: &csv::StringRecord, name: &str| {
+        row[headers.iter().position(|header| header == name).unwrap()].to_string()
+    };
+    assert_eq!(field(&rows[0], "oid"), "1");
+    assert_eq!(field(&rows[0], "gps"), "9");
+    assert_eq!(field(&rows[0], "sog"), "10.5");
+    // missing values are left empty
+    assert_eq!(field(&rows[1], "sog"), "");
+}
+
+#[test]
+fn geojson_writes_a_feature_per_trip() {
+    // trip 0 goes on over two writes, trip 2 has a single point
+    let trajs = [
+        trajectory(1, &[0, 0]),
+        trajectory(1, &[0, 1, 1, 2]),
+        trajectory(2, &[5, 5]),
+    ];
+    let contents = written("trips.geojson", Geometry::Trips, &trajs);
+    let collection: Value = serde_json::from_str(&contents).unwrap();
+    assert_eq!(collection["type"], "FeatureCollection");
+
+    let mut trips: Vec<(i64, i64, String, u64)> = collection["features"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|feature| {
+            let properties = &feature["properties"];
+            (
+                properties["oid"].as_i64().unwrap(),
+                properties["trip"].as_i64().unwrap(),
+                feature["geometry"]["type"].as_str().unwrap().to_string(),
+                properties["points"].as_u64().unwrap(),
+            )
+        })
+        .collect();
+    trips.sort();
+    assert_eq!(
+        trips,
+        [
+            (1, 0, "LineString".to_string(), 3),
+            (1, 1, "LineString".to_string(), 2),
+            (1, 2, "Point".to_string(), 1),
+            (2, 5, "LineString".to_string(), 2),
+        ]
+    );
+}
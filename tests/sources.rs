@@ -0,0 +1,134 @@
+use arrow::array::{ArrayRef, Float32Array, Float64Array, Int64Array, TimestampMillisecondArray};
+use arrow::ipc::writer::FileWriter;
+use arrow::record_batch::RecordBatch;
+use marshal::sources::{open_source, ColumnMapping};
+use marshal::{MarshalError, Record};
+use parquet::arrow::ArrowWriter;
+use std::fs::File;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+// Named like the usual AIS exports, with the times in milliseconds and no cog column
+fn batch() -> RecordBatch {
+    let columns: Vec<(&str, ArrayRef)> = vec![
+        (
+            "mmsi",
+            Arc::new(Int64Array::from(vec![
+                227006760, 227006760, 228051000, 228051000,
+            ])),
+        ),
+        (
+            "ts",
+            Arc::new(TimestampMillisecondArray::from(vec![
+                1_443_650_401_000,
+                1_443_650_411_999,
+                1_443_650_402_000,
+                1_443_650_412_000,
+            ])),
+        ),
+        (
+            "longitude",
+            Arc::new(Float64Array::from(vec![-4.5, -4.501, -4.46, -4.461])),
+        ),
+        (
+            "latitude",
+            Arc::new(Float64Array::from(vec![
+                Some(48.3),
+                Some(48.301),
+                None,
+                Some(48.36),
+            ])),
+        ),
+        (
+            "speed",
+            Arc::new(Float32Array::from(vec![
+                Some(10.5),
+                None,
+                Some(3.0),
+                Some(3.5),
+            ])),
+        ),
+    ];
+    RecordBatch::try_from_iter(columns).unwrap()
+}
+
+fn mapping() -> ColumnMapping {
+    "oid=mmsi,t=ts,lon=longitude,lat=latitude,sog=speed"
+        .parse()
+        .unwrap()
+}
+
+fn path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("marshal-{}-{}", std::process::id(), name))
+}
+
+fn write_parquet(name: &str) -> PathBuf {
+    let path = path(name);
+    let batch = batch();
+    let mut writer =
+        ArrowWriter::try_new(File::create(&path).unwrap(), batch.schema(), None).unwrap();
+    writer.write(&batch).unwrap();
+    writer.close().unwrap();
+    path
+}
+
+fn write_ipc(name: &str) -> PathBuf {
+    let path = path(name);
+    let batch = batch();
+    let mut writer = FileWriter::try_new(File::create(&path).unwrap(), &batch.schema()).unwrap();
+    writer.write(&batch).unwrap();
+    writer.finish().unwrap();
+    path
+}
+
+fn read(path: &PathBuf, mapping: &ColumnMapping) -> Vec<Result<Record, MarshalError>> {
+    let records = open_source(path.to_str().unwrap(), mapping)
+        .unwrap()
+        .collect();
+    std::fs::remove_file(path).unwrap();
+    records
+}
+
+fn assert_decoded(records: Vec<Result<Record, MarshalError>>) {
+    assert_eq!(records.len(), 4);
+
+    let first = records[0].as_ref().unwrap();
+    assert_eq!((first.oid, first.t), (227006760, 1443650401));
+    assert_eq!((first.lon, first.lat), (-4.5, 48.3));
+    assert_eq!(first.sog, Some(10.5));
+    // not in the input
+    assert_eq!(first.cog, None);
+
+    // milliseconds are truncated to the second, a null sog is missing
+    let second = records[1].as_ref().unwrap();
+    assert_eq!(second.t, 1443650411);
+    assert_eq!(second.sog, None);
+
+    // the row without a latitude fails on its own, the next one is read all the same
+    assert!(matches!(records[2], Err(MarshalError::InvalidRecord(_))));
+    let last = records[3].as_ref().unwrap();
+    assert_eq!((last.oid, last.t, last.lat), (228051000, 1443650412, 48.36));
+}
+
+#[test]
+fn reads_parquet_with_mapped_columns() {
+    assert_decoded(read(&write_parquet("mapped.parquet"), &mapping()));
+}
+
+#[test]
+fn reads_arrow_ipc_with_mapped_columns() {
+    assert_decoded(read(&write_ipc("mapped.arrow"), &mapping()));
+}
+
+#[test]
+fn rejects_a_mapped_column_the_input_lacks() {
+    let mapping: ColumnMapping = "oid=mmsi,t=ts,lon=longitude,lat=lat_deg".parse().unwrap();
+    for path in [write_parquet("missing.parquet"), write_ipc("missing.arrow")] {
+        match read(&path, &mapping).first() {
+            Some(Err(MarshalError::Config(message))) => {
+                assert!(message.contains("lat_deg"), "{}", message)
+            }
+            other => panic!("{}: {:?}", path.display(), other.map(|r| r.is_ok())),
+        }
+    }
+}